@@ -3,9 +3,10 @@ use serde::Serialize;
 
 use std::path::PathBuf;
 
-use clap::{ArgGroup, Parser, Subcommand};
+use clap::{ArgGroup, Args, Parser, Subcommand};
 
 use rozen::rcore::key::DiskKey;
+use rozen::snapshot;
 
 #[derive(Parser)]
 #[command(name = "Rozen")]
@@ -46,13 +47,46 @@ pub(crate) enum Commands {
     },
 
     /// Lists all known snapshots
-    List,
+    List {
+        /// Only list the snapshots taken on this host
+        #[arg(long)]
+        host: Option<String>,
+
+        /// Only list the snapshots with this tag
+        #[arg(long)]
+        tag: Option<String>,
+    },
 
     /// Appends a new snapshot
     Append {
-        /// Set a custom name, otherwise datetime is the default
+        #[command(flatten)]
+        record: RecordArgs,
+
+        /// Hash every file instead of trusting the previous snapshot for unchanged files
+        #[arg(long)]
+        force_rehash: bool,
+
+        /// List the files that would be stored and their size without storing anything
+        #[arg(long, conflicts_with = "stdin")]
+        dry_run: bool,
+
+        /// Back up stdin as a single file instead of the configured sources
+        #[arg(long)]
+        stdin: bool,
+
+        /// Name of the file holding stdin
+        #[arg(long, requires = "stdin")]
+        stdin_name: Option<String>,
+    },
+
+    /// Store the entries of a tar stream as a new snapshot
+    Import {
+        #[command(flatten)]
+        record: RecordArgs,
+
+        /// Tar to read, stdin if `-` or not given
         #[arg(short, long)]
-        tag: Option<String>,
+        input: Option<PathBuf>,
     },
 
     /// Fetch a snapshot from remote
@@ -65,12 +99,126 @@ pub(crate) enum Commands {
 
         /// The tag of the snapshot to fetch
         tag: Option<String>,
+
+        /// Only restore paths matching this glob, a directory restores everything under it
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// Skip paths matching this glob
+        #[arg(long)]
+        exclude: Vec<String>,
+
+        /// Restore the recorded uid/gid instead of looking up the recorded user/group names
+        #[arg(long)]
+        numeric_owner: bool,
+
+        /// Restore the rest of the files when some fail verification instead of stopping, exits
+        /// with status 2 if any were left out
+        #[arg(long)]
+        keep_going: bool,
+    },
+
+    /// Show what changed between two snapshots
+    Diff {
+        /// The timestamp of the older snapshot
+        old: String,
+
+        /// The timestamp of the newer snapshot
+        new: String,
+
+        /// The tag of the older snapshot
+        #[arg(long)]
+        old_tag: Option<String>,
+
+        /// The tag of the newer snapshot
+        #[arg(long)]
+        new_tag: Option<String>,
+    },
+
+    /// Stream a snapshot as a tar
+    Export {
+        /// The timestamp of the snapshot
+        timestamp: String,
+
+        /// The tag of the snapshot to export
+        #[arg(long)]
+        tag: Option<String>,
+
+        /// File to write the tar to, stdout if `-` or not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Rewrite packs that are mostly unreferenced content
+    Repack {
+        /// Repack any pack with less than this ratio of live bytes
+        #[arg(long, default_value_t = 0.5, value_parser = parse_ratio)]
+        threshold: f64,
+
+        /// Leave any pack modified within this many hours alone
+        #[arg(long, default_value_t = 24)]
+        grace_hours: u64,
+    },
+
+    /// Delete packs and maps that no snapshot references
+    Gc {
+        /// Only report what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Leave anything modified within this many hours alone
+        #[arg(long, default_value_t = 24)]
+        grace_hours: u64,
     },
 
     /// Test the entire lifecycle
     Test,
 }
 
+// Shared by every command that creates a snapshot
+#[derive(Args)]
+pub(crate) struct RecordArgs {
+    /// Set a custom name, otherwise datetime is the default
+    #[arg(short, long)]
+    pub tag: Option<String>,
+
+    /// Tags to record with the snapshot, the custom name is always one of them
+    #[arg(long, value_delimiter = ',')]
+    pub tags: Vec<String>,
+
+    /// Free form description to record with the snapshot
+    #[arg(long)]
+    pub description: Option<String>,
+}
+
+impl RecordArgs {
+    // The custom name is always one of the tags
+    pub(crate) fn record(&self) -> snapshot::Record {
+        let mut tags = self.tags.clone();
+        if let Some(tag) = &self.tag
+            && !tags.contains(tag)
+        {
+            tags.insert(0, tag.clone());
+        }
+
+        snapshot::Record {
+            tags,
+            description: self.description.clone(),
+            ..snapshot::Record::default()
+        }
+    }
+}
+
+// A ratio within 0..=1, NaN is rejected as well
+fn parse_ratio(arg: &str) -> Result<f64, String> {
+    let ratio: f64 = arg.parse().map_err(|e| format!("{e}"))?;
+    if (0.0..=1.0).contains(&ratio) {
+        Ok(ratio)
+    } else {
+        Err(format!("{ratio} is outside of 0..=1"))
+    }
+}
+
 // Configuration
 // At a later time honor: https://aws.amazon.com/blogs/security/a-new-and-standardized-way-to-manage-credentials-in-the-aws-sdks/
 // envy = "0.4.2" - for grabbing the env vars via serde
@@ -81,19 +229,34 @@ pub(crate) struct Config {
 
     pub sources: Vec<Source>,
 
+    #[serde(flatten)]
+    pub options: snapshot::Options,
+
     // credentials
     pub disk_key: Option<DiskKey>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub(crate) struct Source {
+    // Name recorded in the index, defaults to the includes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    // Roots to walk, and globs (gitignore style) to skip
     pub include: Vec<String>,
+    #[serde(default)]
     pub exclude: Vec<String>,
 
     #[serde(rename = "type")]
     pub typ: SourceType,
 }
 
+impl Source {
+    pub(crate) fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.include.join(","))
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Copy)]
 pub(crate) enum SourceType {
     AppendOnly,
@@ -1,8 +1,15 @@
 use ignore::WalkBuilder;
+use ignore::overrides::OverrideBuilder;
 
 use std::error::Error;
-use std::io::{Read, Write};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+use std::time::SystemTime;
 use tempfile::TempDir;
 
 use clap::Parser as _;
@@ -17,23 +24,37 @@ use crate::cli::Commands;
 use rozen::rcore::crypto;
 use rozen::rcore::key;
 
+use rozen::gc;
 use rozen::remote;
 use rozen::remote::Remote;
 use rozen::remote::Typ;
+use rozen::repack;
 
 use rozen::snapshot;
 
+// Exit status of a snapshot that got stored but left some files out or stored files that kept
+// changing while being read, or of a restore that kept going past files that failed verification,
+// scripts can tell it apart from the command failing
+const EXIT_WARNINGS: u8 = 2;
+
+#[derive(Debug)]
+struct Warnings(usize);
+
+impl fmt::Display for Warnings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Completed with {} warnings", self.0)
+    }
+}
+
+impl Error for Warnings {}
+
 // TODO: should name various things like Index getting its own hashkey
 //  * I-<timestamp> = index
 //  * P-<rng>  = packfile (only one that isn't hash)
 //  * B-<hash> = raw blob (big files)
-// TODO: how to handle files larger than X size (ie S3 only allow file up to X for eg)
-//  * Do we want to support chunking, could possibly do it via
-//  * B-<hash>.p0
-//  * B-<hash>.p1
-//  * B-<hash>.p? - I'm not sure, could have B-<hash> -> metadata -> B-<hash>.p? but could
-//      also just always have the B-<hash> xor B-<hash>.p?
-fn main() -> Result<(), Box<dyn Error>> {
+// Files larger than the max pack size (ie S3 only allow file up to X for eg) are split by the
+// ObjectStore into multiple packs, one per part, with the map tracking hash + part -> pack
+fn main() -> Result<ExitCode, Box<dyn Error>> {
     env_logger::init();
     crypto::init()?;
 
@@ -58,74 +79,167 @@ fn main() -> Result<(), Box<dyn Error>> {
 
             // For now just focus on figuring out some sort of key management/generation here
             init(&mut config_content, password)?;
-            Ok(())
+            Ok(ExitCode::SUCCESS)
         }
-        Some(Commands::List) => list(&remote),
-        Some(Commands::Append { tag }) => {
+        Some(Commands::Test) => test(&mut remote, password).map(|()| ExitCode::SUCCESS),
+        Some(command) => match run(command, &mut remote, password) {
+            Err(e) if e.is::<Warnings>() => {
+                println!("{e}");
+                Ok(ExitCode::from(EXIT_WARNINGS))
+            }
+            result => result.map(|()| ExitCode::SUCCESS),
+        },
+        None => Ok(ExitCode::SUCCESS),
+    }
+}
+
+// Every command past init works off the config stored in the remote
+fn run<B: Remote>(
+    command: &Commands,
+    remote: &mut B,
+    password: &str,
+) -> Result<(), Box<dyn Error>> {
+    let config = load_config(remote)?;
+
+    match command {
+        Commands::List { host, tag } => {
+            let (host, tag) = (host.as_deref(), tag.as_deref());
+
+            list(&config, password, remote, host, tag)
+        }
+        Commands::Append {
+            record,
+            force_rehash,
+            dry_run,
+            stdin,
+            stdin_name,
+        } => {
             let timestamp = OffsetDateTime::now_utc();
 
-            let mut config_content = remote.read_filename(Typ::TEST, "CONFIG")?;
-            let mut config_str = String::new();
-            config_content.read_to_string(&mut config_str)?;
-            let config: cli::Config = toml::from_str(&config_str)?;
+            if *dry_run {
+                plan(&config, password, remote, record, *force_rehash)
+            } else if *stdin {
+                let stream = snapshot::Stream::File {
+                    name: stdin_name.clone().unwrap_or_else(|| "stdin".to_owned()),
+                    reader: io::stdin().lock(),
+                };
+                append_stream(&config, password, remote, timestamp, record, stream)
+            } else {
+                append(&config, password, remote, timestamp, record, *force_rehash)
+            }
+        }
+        Commands::Import { record, input } => {
+            let timestamp = OffsetDateTime::now_utc();
+            let reader: Box<dyn Read> = match input {
+                Some(path) if path != Path::new("-") => Box::new(File::open(path)?),
+                _ => Box::new(io::stdin().lock()),
+            };
 
-            append(&config, password, &mut remote, timestamp, tag.clone())
+            let stream = snapshot::Stream::Tar(BufReader::new(reader));
+            append_stream(&config, password, remote, timestamp, record, stream)
         }
-        Some(Commands::Fetch {
+        Commands::Fetch {
             timestamp,
             tag,
             dir,
-        }) => {
+            include,
+            exclude,
+            numeric_owner,
+            keep_going,
+        } => {
             let timestamp = OffsetDateTime::parse(timestamp, &Rfc3339)?;
             let target = dir.as_path();
-
-            let mut config_content = remote.read_filename(Typ::TEST, "CONFIG")?;
-            let mut config_str = String::new();
-            config_content.read_to_string(&mut config_str)?;
-            let config: cli::Config = toml::from_str(&config_str)?;
+            let options = snapshot::RestoreOptions {
+                filter: snapshot::Filter {
+                    include: include.clone(),
+                    exclude: exclude.clone(),
+                },
+                numeric_owner: *numeric_owner,
+                keep_going: *keep_going,
+            };
 
             fetch(
                 &config,
                 password,
-                &mut remote,
+                remote,
                 timestamp,
                 tag.clone(),
                 target,
+                &options,
             )
         }
-        Some(Commands::Test) => {
-            println!("TEST ONLY");
-            let timestamp = OffsetDateTime::now_utc();
-            let tag = Some("TEST".to_owned());
-            let target = TempDir::new()?;
-
-            let mut config_content = remote.write_multi_filename(Typ::TEST, "CONFIG")?;
-            init(&mut config_content, password)?;
-
-            let mut config_content = remote.read_filename(Typ::TEST, "CONFIG")?;
-            let mut config_str = String::new();
-            config_content.read_to_string(&mut config_str)?;
-            let config: cli::Config = toml::from_str(&config_str)?;
+        Commands::Diff {
+            old,
+            new,
+            old_tag,
+            new_tag,
+        } => {
+            let old = (OffsetDateTime::parse(old, &Rfc3339)?, old_tag.clone());
+            let new = (OffsetDateTime::parse(new, &Rfc3339)?, new_tag.clone());
+
+            diff(&config, password, remote, old, new)
+        }
+        Commands::Export {
+            timestamp,
+            tag,
+            output,
+        } => {
+            let timestamp = OffsetDateTime::parse(timestamp, &Rfc3339)?;
 
-            append(&config, password, &mut remote, timestamp, tag.clone())?;
-            fetch(
+            export(
                 &config,
                 password,
-                &mut remote,
+                remote,
                 timestamp,
                 tag.clone(),
-                target.path(),
-            )?;
-            list(&remote)?;
-
-            // TODO: add support to picking an target/combo and verifying
-            verify(&config, password, &mut remote, timestamp, tag)
+                output.as_deref(),
+            )
         }
-        None => Ok(()),
+        Commands::Repack {
+            threshold,
+            grace_hours,
+        } => repack(&config, password, remote, *threshold, *grace_hours),
+        Commands::Gc {
+            dry_run,
+            grace_hours,
+        } => gc(&config, password, remote, *grace_hours, *dry_run),
+        Commands::Init { .. } | Commands::Test => Err("Handled before loading the config".into()),
     }
 }
 
-fn init(config_content: &mut Box<dyn Write>, password: &str) -> Result<(), Box<dyn Error>> {
+fn test<B: Remote>(remote: &mut B, password: &str) -> Result<(), Box<dyn Error>> {
+    println!("TEST ONLY");
+    let timestamp = OffsetDateTime::now_utc();
+    let tag = Some("TEST".to_owned());
+    let target = TempDir::new()?;
+
+    let mut config_content = remote.write_multi_filename(Typ::TEST, "CONFIG")?;
+    init(&mut config_content, password)?;
+
+    let config = load_config(remote)?;
+
+    let args = cli::RecordArgs {
+        tag: tag.clone(),
+        tags: vec![],
+        description: None,
+    };
+    append(&config, password, remote, timestamp, &args, false)?;
+    fetch(
+        &config,
+        password,
+        remote,
+        timestamp,
+        tag.clone(),
+        target.path(),
+        &snapshot::RestoreOptions::default(),
+    )?;
+    list(&config, password, remote, None, None)?;
+
+    // TODO: add support to picking an target/combo and verifying
+    verify(&config, password, remote, timestamp, tag)
+}
+
+fn init(config_content: &mut Box<dyn Write + Send>, password: &str) -> Result<(), Box<dyn Error>> {
     // TODO: make all of this much beter, ie maybe make it generate all of the needed
     // stuff at the top then generate commented out sample section and then go from there
     let mut sample_config: cli::Config = toml::from_str(
@@ -141,6 +255,7 @@ fn init(config_content: &mut Box<dyn Write>, password: &str) -> Result<(), Box<d
         "#,
     )?;
     info!("CONFIG: {sample_config:?}");
+    sample_config.options.validate()?;
 
     // Generate a new MemKey and convert it to DiskKey to store in the config
     let key = key::MemKey::new();
@@ -156,68 +271,278 @@ fn init(config_content: &mut Box<dyn Write>, password: &str) -> Result<(), Box<d
     Ok(())
 }
 
-fn list<B: Remote>(remote: &B) -> Result<(), Box<dyn Error>> {
-    // TODO: add the following fields/option
-    // 1. timestamp
-    // 2. size (of stored backup?)
-    // 3. Prefix name - I-{timestamp}-{name}
-    for key in remote.list_keys(Typ::Index)? {
-        let (typ, odt, tag) = from_key(&key)?;
+fn load_config<B: Remote>(remote: &mut B) -> Result<cli::Config, Box<dyn Error>> {
+    let mut config_content = remote.read_filename(Typ::TEST, "CONFIG")?;
+    let mut config_str = String::new();
+    config_content.read_to_string(&mut config_str)?;
+
+    let config: cli::Config = toml::from_str(&config_str)?;
+    config.options.validate()?;
+    Ok(config)
+}
+
+fn list<B: Remote>(
+    config: &cli::Config,
+    password: &str,
+    remote: &mut B,
+    host: Option<&str>,
+    tag: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let key = config
+        .disk_key
+        .as_ref()
+        .ok_or("config")?
+        .to_mem_key(password)?;
+
+    for index in remote.list_keys(Typ::Index)? {
+        let record = snapshot::record(&key, &mut remote.read_filename(Typ::Index, &index)?)?;
+
+        // Snapshots without a record can't match a filter
+        let matches = |record: &snapshot::Record| {
+            host.is_none_or(|h| record.hostname.as_deref() == Some(h))
+                && tag.is_none_or(|t| record.tags.iter().any(|x| x == t))
+        };
+        if (host.is_some() || tag.is_some()) && !record.as_ref().is_some_and(matches) {
+            continue;
+        }
+
+        let (typ, odt, tag) = from_key(&index)?;
         println!(
             "Typ: {}, odt: {}, tag: {:?}",
             typ,
             odt.format(&Rfc3339)?,
             tag
         );
+        if let Some(record) = record {
+            print_record(&record)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_record(record: &snapshot::Record) -> Result<(), Box<dyn Error>> {
+    let time = |t: Option<SystemTime>| -> Result<String, Box<dyn Error>> {
+        t.map_or(Ok("-".to_owned()), |t| {
+            Ok(OffsetDateTime::from(t).format(&Rfc3339)?)
+        })
+    };
+
+    println!(
+        "\tHost: {}, user: {}, version: {}",
+        record.hostname.as_deref().unwrap_or("-"),
+        record.username.as_deref().unwrap_or("-"),
+        record.version
+    );
+    println!(
+        "\tStart: {}, end: {}",
+        time(record.start)?,
+        time(record.end)?
+    );
+    if !record.paths.is_empty() {
+        println!("\tPaths: {}", record.paths.join(", "));
+    }
+    if !record.tags.is_empty() {
+        println!("\tTags: {}", record.tags.join(", "));
     }
+    if let Some(parent) = &record.parent {
+        println!("\tParent: {}", parent.index);
+    }
+    if let Some(description) = &record.description {
+        println!("\tDescription: {description}");
+    }
+    print_stats(&record.stats);
     Ok(())
 }
 
+fn print_stats(stats: &snapshot::Stats) {
+    println!(
+        "\tFiles: {} scanned, {} new, {} changed, {} unchanged, {} skipped",
+        stats.files_scanned,
+        stats.files_new,
+        stats.files_changed,
+        stats.files_unchanged,
+        stats.files_skipped
+    );
+    println!(
+        "\tAdded: {} bytes in {} packs, {} bytes read, {} bytes compressed, {} dedup hits",
+        stats.bytes_uploaded,
+        stats.packs_written,
+        stats.bytes_read,
+        stats.bytes_compressed,
+        stats.dedup_hits
+    );
+    println!(
+        "\tElapsed: parent {:?}, ingest {:?}, finalize {:?}",
+        stats.parent_elapsed, stats.ingest_elapsed, stats.finalize_elapsed
+    );
+    for skipped in &stats.skipped {
+        println!("\tSkipped: {} - {}", skipped.path, skipped.reason);
+    }
+    for path in &stats.unstable {
+        println!("\tChanged while being read: {path}");
+    }
+}
+
 fn append<B: Remote>(
     config: &cli::Config,
     password: &str,
     remote: &mut B,
     timestamp: OffsetDateTime,
-    tag: Option<String>,
+    args: &cli::RecordArgs,
+    force_rehash: bool,
 ) -> Result<(), Box<dyn Error>> {
-    // Config bits
-    let target = config
-        .sources
-        .first()
-        .ok_or("config")?
-        .include
-        .first()
-        .ok_or("config")?;
-    let _xclude = config
-        .sources
-        .first()
-        .ok_or("config")?
-        .exclude
-        .first()
-        .ok_or("config")?;
-    let _stype = config.sources.first().ok_or("config")?.typ;
     let key = config
         .disk_key
         .as_ref()
         .ok_or("config")?
         .to_mem_key(password)?;
 
+    let mut record = args.record();
+    record.paths = source_paths(config);
+    if !force_rehash {
+        record.parent = parent_snapshot(&key, remote, &record.paths, args.tag.as_deref())?;
+    }
+
     // Store indexer + Map
-    let (mut index_content, mut map_content) = write_snapshot(remote, timestamp, tag)?;
+    let (mut index_content, mut map_content) = write_snapshot(remote, timestamp, args.tag.clone())?;
 
     // Perform an appending snapshot
-    snapshot::append(
+    let stats = snapshot::append(
         &key,
         remote,
         &mut index_content,
         &mut map_content,
-        WalkBuilder::new(target)
-            .follow_links(config.symlink)
-            .standard_filters(false)
-            .same_file_system(config.same_fs)
-            .sort_by_file_name(Ord::cmp)
-            .build(),
-    )
+        sources(config)?,
+        &config.options,
+        record,
+    )?;
+    print_stats(&stats);
+
+    match stats.skipped.len() + stats.unstable.len() {
+        0 => Ok(()),
+        warnings => Err(Warnings(warnings).into()),
+    }
+}
+
+// Same change detection as `append` but nothing gets stored
+fn plan<B: Remote>(
+    config: &cli::Config,
+    password: &str,
+    remote: &mut B,
+    args: &cli::RecordArgs,
+    force_rehash: bool,
+) -> Result<(), Box<dyn Error>> {
+    let key = config
+        .disk_key
+        .as_ref()
+        .ok_or("config")?
+        .to_mem_key(password)?;
+
+    let parent = if force_rehash {
+        None
+    } else {
+        parent_snapshot(&key, remote, &source_paths(config), args.tag.as_deref())?
+    };
+    let plan = snapshot::plan(
+        &key,
+        remote,
+        sources(config)?,
+        &config.options,
+        parent.as_ref(),
+    )?;
+
+    for (change, path, size) in &plan.files {
+        let label = match change {
+            snapshot::Change::Added => "ADD",
+            _ => "MOD",
+        };
+        println!("{label:4} {} ({size} bytes)", path.display());
+    }
+    println!(
+        "Would store {} files, {} bytes before dedup + compression",
+        plan.files.len(),
+        plan.new_bytes
+    );
+    println!(
+        "\tFiles: {} unchanged, {} skipped",
+        plan.files_unchanged, plan.files_skipped
+    );
+    for skipped in &plan.skipped {
+        println!("\tSkipped: {} - {}", skipped.path, skipped.reason);
+    }
+    Ok(())
+}
+
+// Streams have nothing to compare against so there is no parent
+fn append_stream<B: Remote, R: Read>(
+    config: &cli::Config,
+    password: &str,
+    remote: &mut B,
+    timestamp: OffsetDateTime,
+    args: &cli::RecordArgs,
+    stream: snapshot::Stream<R>,
+) -> Result<(), Box<dyn Error>> {
+    let key = config
+        .disk_key
+        .as_ref()
+        .ok_or("config")?
+        .to_mem_key(password)?;
+
+    let mut record = args.record();
+    if let snapshot::Stream::File { name, .. } = &stream {
+        record.paths = vec![name.clone()];
+    }
+
+    let (mut index_content, mut map_content) = write_snapshot(remote, timestamp, args.tag.clone())?;
+    let stats = snapshot::append_stream(
+        &key,
+        remote,
+        &mut index_content,
+        &mut map_content,
+        stream,
+        &config.options,
+        record,
+    )?;
+    print_stats(&stats);
+
+    match stats.skipped.len() {
+        0 => Ok(()),
+        warnings => Err(Warnings(warnings).into()),
+    }
+}
+
+// One walker per include root so that the excludes are anchored to that root
+fn sources(config: &cli::Config) -> Result<Vec<snapshot::Source>, Box<dyn Error>> {
+    if config.sources.is_empty() {
+        return Err("config: no sources".into());
+    }
+
+    let mut ret = vec![];
+    for source in &config.sources {
+        let mut walkers = vec![];
+        for include in &source.include {
+            let mut overrides = OverrideBuilder::new(include);
+            for exclude in &source.exclude {
+                overrides.add(&format!("!{exclude}"))?;
+            }
+
+            walkers.push(
+                WalkBuilder::new(include)
+                    .follow_links(config.symlink)
+                    .standard_filters(false)
+                    .same_file_system(config.same_fs)
+                    .overrides(overrides.build()?)
+                    .sort_by_file_name(Ord::cmp)
+                    .build(),
+            );
+        }
+
+        ret.push(snapshot::Source {
+            name: source.name(),
+            walkers,
+        });
+    }
+    Ok(ret)
 }
 
 fn fetch<B: Remote>(
@@ -227,6 +552,7 @@ fn fetch<B: Remote>(
     timestamp: OffsetDateTime,
     tag: Option<String>,
     target: &Path,
+    options: &snapshot::RestoreOptions,
 ) -> Result<(), Box<dyn Error>> {
     let (mut index_content, mut map_content) = read_snapshot(remote, timestamp, tag.clone())?;
     let (_, mut map_content_2) = read_snapshot(remote, timestamp, tag)?;
@@ -236,14 +562,102 @@ fn fetch<B: Remote>(
         .ok_or("dadf")?
         .to_mem_key(password)?;
 
-    snapshot::fetch(
+    let report = snapshot::fetch(
         &key,
         remote,
         &mut index_content,
         &mut map_content,
         &mut map_content_2,
         target,
-    )
+        options,
+    )?;
+
+    for path in &report.mismatched {
+        println!("Hash mismatch: {path}");
+    }
+    println!(
+        "Restored {} files, {} mismatched",
+        report.restored,
+        report.mismatched.len()
+    );
+
+    match report.mismatched.len() {
+        0 => Ok(()),
+        warnings => Err(Warnings(warnings).into()),
+    }
+}
+
+fn export<B: Remote>(
+    config: &cli::Config,
+    password: &str,
+    remote: &mut B,
+    timestamp: OffsetDateTime,
+    tag: Option<String>,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let (mut index_content, mut map_content) = read_snapshot(remote, timestamp, tag.clone())?;
+    let (_, mut map_content_2) = read_snapshot(remote, timestamp, tag)?;
+    let key = config
+        .disk_key
+        .as_ref()
+        .ok_or("config")?
+        .to_mem_key(password)?;
+
+    let writer: Box<dyn Write> = match output {
+        Some(path) if path != Path::new("-") => Box::new(File::create(path)?),
+        _ => Box::new(io::stdout().lock()),
+    };
+    let mut writer = snapshot::export_tar(
+        &key,
+        remote,
+        &mut index_content,
+        &mut map_content,
+        &mut map_content_2,
+        BufWriter::new(writer),
+    )?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn diff<B: Remote>(
+    config: &cli::Config,
+    password: &str,
+    remote: &mut B,
+    (old, old_tag): (OffsetDateTime, Option<String>),
+    (new, new_tag): (OffsetDateTime, Option<String>),
+) -> Result<(), Box<dyn Error>> {
+    let mut old_index = remote.read_filename(Typ::Index, &to_key("I", old, old_tag))?;
+    let mut new_index = remote.read_filename(Typ::Index, &to_key("I", new, new_tag))?;
+    let key = config
+        .disk_key
+        .as_ref()
+        .ok_or("config")?
+        .to_mem_key(password)?;
+
+    let diff = snapshot::diff(&key, &mut old_index, &mut new_index)?;
+
+    let mut counts = [0; 4];
+    for (change, path) in &diff.changes {
+        let (idx, label) = match change {
+            snapshot::Change::Added => (0, "ADD"),
+            snapshot::Change::Removed => (1, "DEL"),
+            snapshot::Change::Modified => (2, "MOD"),
+            snapshot::Change::Metadata => (3, "META"),
+        };
+        counts[idx] += 1;
+        println!("{label:4} {path}");
+    }
+    println!(
+        "Added: {} ({} bytes), removed: {} ({} bytes), modified: {} ({} bytes), metadata only: {}",
+        counts[0],
+        diff.added_bytes,
+        counts[1],
+        diff.removed_bytes,
+        counts[2],
+        diff.modified_bytes,
+        counts[3]
+    );
+    Ok(())
 }
 
 // TODO: add a verify_all to validate the entire backup archive
@@ -264,12 +678,138 @@ fn verify<B: Remote>(
     snapshot::verify(&key, remote, &mut index_content, &mut map_content)
 }
 
+fn repack<B: Remote>(
+    config: &cli::Config,
+    password: &str,
+    remote: &mut B,
+    threshold: f64,
+    grace_hours: u64,
+) -> Result<(), Box<dyn Error>> {
+    let key = config
+        .disk_key
+        .as_ref()
+        .ok_or("config")?
+        .to_mem_key(password)?;
+
+    let report = repack::repack(
+        &key,
+        remote,
+        &list_snapshots(remote)?,
+        &config.options,
+        threshold,
+        Duration::from_hours(grace_hours),
+    )?;
+
+    println!(
+        "Packs removed: {}, written: {}, live bytes: {}, dead bytes: {}",
+        report.packs_removed, report.packs_written, report.live_bytes, report.dead_bytes
+    );
+    if report.recent + report.checkpointed > 0 {
+        println!(
+            "Left {} recent packs and {} packs of unfinished appends alone",
+            report.recent, report.checkpointed
+        );
+    }
+    Ok(())
+}
+
+fn gc<B: Remote>(
+    config: &cli::Config,
+    password: &str,
+    remote: &mut B,
+    grace_hours: u64,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let key = config
+        .disk_key
+        .as_ref()
+        .ok_or("config")?
+        .to_mem_key(password)?;
+
+    let grace = Duration::from_hours(grace_hours);
+    let report = gc::gc(&key, remote, &list_snapshots(remote)?, grace, dry_run)?;
+
+    let verb = if dry_run { "Would delete" } else { "Deleted" };
+    for pack in &report.packs {
+        println!("{verb} pack: {pack}");
+    }
+    for map in &report.maps {
+        println!("{verb} map: {map}");
+    }
+    println!(
+        "{verb} {} packs, {} maps, {} bytes, {} recent files skipped",
+        report.packs.len(),
+        report.maps.len(),
+        report.bytes,
+        report.recent
+    );
+    if report.checkpoints > 0 {
+        println!(
+            "Kept the packs of {} unfinished appends, the next append on each host resumes from them",
+            report.checkpoints
+        );
+    }
+    Ok(())
+}
+
+// Every snapshot in the remote, keyed off the index
+fn list_snapshots<B: Remote>(remote: &B) -> Result<Vec<snapshot::Keys>, Box<dyn Error>> {
+    let mut ret = vec![];
+    for key in remote.list_keys(Typ::Index)? {
+        let (_, odt, tag) = from_key(&key)?;
+        ret.push(snapshot::Keys {
+            map: to_key("M", odt, tag.map(str::to_owned)),
+            index: key,
+        });
+    }
+    Ok(ret)
+}
+
+// Roots of the sources that gets backed up
+fn source_paths(config: &cli::Config) -> Vec<String> {
+    config
+        .sources
+        .iter()
+        .flat_map(|source| source.include.iter().cloned())
+        .collect()
+}
+
+// The parent for change detection is the latest snapshot of the same paths from this host, and of
+// the same name if the new snapshot is named. Snapshots without a record can't match
+fn parent_snapshot<B: Remote>(
+    key: &key::MemKey,
+    remote: &mut B,
+    paths: &[String],
+    tag: Option<&str>,
+) -> Result<Option<snapshot::Keys>, Box<dyn Error>> {
+    let hostname = snapshot::hostname()?;
+    let mut snapshots = vec![];
+    for keys in list_snapshots(remote)? {
+        let (_, odt, _) = from_key(&keys.index)?;
+        snapshots.push((odt, keys));
+    }
+    snapshots.sort_by_key(|(odt, _)| std::cmp::Reverse(*odt));
+
+    for (_, keys) in snapshots {
+        let record = snapshot::record(key, &mut remote.read_filename(Typ::Index, &keys.index)?)?;
+        let matches = record.is_some_and(|record| {
+            record.hostname == hostname
+                && record.paths == paths
+                && tag.is_none_or(|t| record.tags.iter().any(|x| x == t))
+        });
+        if matches {
+            return Ok(Some(keys));
+        }
+    }
+    Ok(None)
+}
+
 #[expect(clippy::type_complexity)]
 fn read_snapshot<B: Remote>(
     remote: &mut B,
     timestamp: OffsetDateTime,
     tag: Option<String>,
-) -> Result<(Box<dyn Read>, Box<dyn Read>), Box<dyn Error>> {
+) -> Result<(Box<dyn Read + Send>, Box<dyn Read + Send>), Box<dyn Error>> {
     let index_content = remote.read_filename(Typ::Index, &to_key("I", timestamp, tag.clone()))?;
     let map_content = remote.read_filename(Typ::Map, &to_key("M", timestamp, tag))?;
 
@@ -281,7 +821,7 @@ fn write_snapshot<B: Remote>(
     remote: &B,
     timestamp: OffsetDateTime,
     tag: Option<String>,
-) -> Result<(Box<dyn Write>, Box<dyn Write>), Box<dyn Error>> {
+) -> Result<(Box<dyn Write + Send>, Box<dyn Write + Send>), Box<dyn Error>> {
     let index_content =
         remote.write_multi_filename(Typ::Index, &to_key("I", timestamp, tag.clone()))?;
 
@@ -1,13 +1,18 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::io::BufReader;
 use std::io::Cursor;
 use std::io::Read;
 use std::io::Write;
 
+use log::{debug, info};
+
+use crate::rcore::compress;
 use crate::rcore::hash;
 use crate::rcore::key;
 
 use crate::rarc::pack::PackBuilder;
+use crate::rarc::pack::PackConfig;
 use crate::rarc::pack::PackOut;
 
 use crate::remote::Remote;
@@ -49,57 +54,173 @@ use crate::sql::Map;
 //      * Manage s3/glacier/deep-freeze lifecycle (adjecent system, not in backend directly)
 pub(crate) struct ObjectStore<'a, B: Remote> {
     remote: &'a B,
-    current_pack: Option<PackBuilder<Box<dyn Write>>>,
+    current_pack: Option<PackBuilder<Box<dyn Write + Send>>>,
     map: Map,
+    config: PackConfig,
+
+    // Count + total size of the packs uploaded so far
+    packs: u64,
+    uploaded: u64,
+
+    checkpoint: Option<Checkpoint>,
+}
+
+// The map of the packs that finished uploading gets written out every so often so that an
+// interrupted append leaves a record of them behind, the next append under the same name picks
+// it up and reuses the content instead of storing it again
+pub(crate) struct Checkpoint {
+    pub name: String,
+
+    // Packs to upload between writing out checkpoints, 0 to never write one
+    pub every: u64,
+    pub policy: compress::Policy,
+
+    // Content stored by the earlier append, it stays in every checkpoint till the append is done
+    pub resumed: Map,
+
+    // Packs uploaded as of the last checkpoint
+    pub written: u64,
 }
 
 impl<'a, B: Remote> ObjectStore<'a, B> {
-    pub(crate) fn new(remote: &'a B) -> Result<Self, Box<dyn Error>> {
+    pub(crate) fn new(remote: &'a B, config: &PackConfig) -> Result<Self, Box<dyn Error>> {
         // TODO: later do something like fetch the latest cache and use that
         Ok(ObjectStore {
             remote,
             current_pack: None,
             map: Map::new()?,
+            config: config.clone(),
+            packs: 0,
+            uploaded: 0,
+            checkpoint: None,
         })
     }
 
+    pub(crate) fn with_checkpoint(mut self, checkpoint: Checkpoint) -> Self {
+        self.checkpoint = Some(checkpoint);
+        self
+    }
+
     pub(crate) fn append<R: Read>(
         &mut self,
         hash: hash::Hash,
         key: &key::MemKey,
         reader: &mut R,
         size: u64,
+        comp: compress::Compression,
     ) -> Result<(), Box<dyn Error>> {
-        let pack_id = if size > (3 * 1024) {
-            self.append_big(hash, key, reader)?
+        // Content is already stored, nothing to do
+        if !self.map.find_packs(hash)?.is_empty() {
+            return Ok(());
+        }
+
+        // Stored by an earlier append that got interrupted
+        if let Some(checkpoint) = &self.checkpoint
+            && self.carry(hash, &checkpoint.resumed)?
+        {
+            info!("RESUMED: {}", hash::to_hex(hash));
+            return Ok(());
+        }
+
+        if size > self.config.small_file {
+            let parts = self.append_big(hash, key, reader)?;
+            for (part, (pack_id, length)) in parts.into_iter().enumerate() {
+                self.map
+                    .insert_chunk(hash, u32::try_from(part)?, pack_id, comp, length)?;
+            }
         } else {
-            self.append_small(hash, key, reader)?
-        };
+            let (pack_id, length) = self.append_small(hash, key, reader)?;
+            self.map.insert_chunk(hash, 0, pack_id, comp, length)?;
+        }
 
-        self.map.insert_chunk(hash, pack_id)?;
+        if let Some(checkpoint) = &self.checkpoint
+            && checkpoint.every > 0
+            && self.packs >= checkpoint.written + checkpoint.every
+        {
+            self.write_checkpoint(key)?;
+        }
+        Ok(())
+    }
+
+    // Carry the content over from the parent snapshot's map, the packs are shared between the
+    // snapshots so nothing gets stored again
+    pub(crate) fn reuse(&self, hash: hash::Hash, parent: &Map) -> Result<(), Box<dyn Error>> {
+        if !self.map.find_packs(hash)?.is_empty() {
+            return Ok(());
+        }
+
+        if !self.carry(hash, parent)? {
+            return Err(format!("Content missing from parent map: {}", hash::to_hex(hash)).into());
+        }
+        Ok(())
+    }
+
+    // Points the content at the packs of the other map, false if the other map doesn't have it
+    fn carry(&self, hash: hash::Hash, other: &Map) -> Result<bool, Box<dyn Error>> {
+        let parts = other.find_parts(hash)?;
+        if parts.is_empty() {
+            return Ok(false);
+        }
+
+        let comp = other.find_compression(hash)?;
+        for (part, (pack_id, length)) in parts.into_iter().enumerate() {
+            self.map
+                .insert_chunk(hash, u32::try_from(part)?, pack_id, comp, length)?;
+            if let Some(records) = other.find_pack(pack_id)? {
+                self.map.insert_pack(pack_id, records)?;
+            }
+        }
+        Ok(true)
+    }
+
+    // Only the packs that are done uploading goes in, the current pack may never make it
+    fn write_checkpoint(&mut self, key: &key::MemKey) -> Result<(), Box<dyn Error>> {
+        let checkpoint = self.checkpoint.as_mut().ok_or("checkpoint")?;
+        let map = self.map.copy()?;
+        if let Some(pack) = &self.current_pack {
+            map.remove_pack(pack.id)?;
+        }
+        map.merge(&checkpoint.resumed)?;
+
+        debug!("CHECKPOINT: {} - {} packs", checkpoint.name, self.packs);
+        let writer = self
+            .remote
+            .write_multi_filename(Typ::Checkpoint, &checkpoint.name)?;
+        map.unload(key, &checkpoint.policy, writer)?;
+        checkpoint.written = self.packs;
         Ok(())
     }
 
     fn append_big<R: Read>(
-        &self,
+        &mut self,
         hash: hash::Hash,
         key: &key::MemKey,
         reader: &mut R,
-    ) -> Result<hash::Hash, Box<dyn Error>> {
+    ) -> Result<Vec<(hash::Hash, u64)>, Box<dyn Error>> {
         // This one focuses on reading in one single big file into its own packfile and uploading
-        // it as it is
-        let mut temp_pack = {
-            let pack_id = key.gen_id();
-            let multiwrite = self.remote.write_multi(Typ::Pack, pack_id)?;
-            PackBuilder::new(pack_id, multiwrite)?
-        };
+        // it as it is, if its larger than the max pack size it gets split into multiple parts with
+        // each part going into its own packfile
+        let mut reader = BufReader::new(reader);
+        let mut packs = vec![];
 
-        temp_pack.append(hash, reader)?;
+        loop {
+            let mut temp_pack = {
+                let pack_id = key.gen_id();
+                let multiwrite = self.remote.write_multi(Typ::Pack, pack_id)?;
+                PackBuilder::new(pack_id, multiwrite, self.config.max_size)?
+            };
 
-        let pack_id = temp_pack.id;
-        temp_pack.finalize(key)?;
+            let start = temp_pack.records_size()?;
+            let more = temp_pack.append_part(hash, &mut reader, self.config.max_size)?;
 
-        Ok(pack_id)
+            packs.push((temp_pack.id, temp_pack.records_size()? - start));
+            self.finalize_pack(temp_pack, key)?;
+
+            if !more {
+                break;
+            }
+        }
+        Ok(packs)
     }
 
     fn append_small<R: Read>(
@@ -107,52 +228,60 @@ impl<'a, B: Remote> ObjectStore<'a, B> {
         hash: hash::Hash,
         key: &key::MemKey,
         reader: &mut R,
-    ) -> Result<hash::Hash, Box<dyn Error>> {
-        // Stream the data into the pack
-        // TODO:
-        //  1. compression complicates things for things below a certain
-        //     size don't bother compressing?
-        //  2. for things above a size try compressing
-        //  3. See if i can't do fast bail out but would require
-        //     spooling....
-        //  4. If below certain size go ahead and pack it up
-        //  5. if above certain size just send it as its own archive to
-        //     backend
+    ) -> Result<(hash::Hash, u64), Box<dyn Error>> {
+        // Stream the data into the pack, the compression policy upstream already decided
+        // if this was worth compressing or not
         let temp_pack = if let Some(v) = self.current_pack.as_mut() {
             v
         } else {
             let pack_id = key.gen_id();
             let multiwrite = self.remote.write_multi(Typ::Pack, pack_id)?;
-            self.current_pack
-                .insert(PackBuilder::new(pack_id, multiwrite)?)
+            self.current_pack.insert(PackBuilder::new(
+                pack_id,
+                multiwrite,
+                self.config.target_size,
+            )?)
         };
-        let pack_id = temp_pack.id;
+        let (pack_id, start) = (temp_pack.id, temp_pack.records_size()?);
 
-        if temp_pack.append(hash, reader)? {
-            self.current_pack.take().ok_or("pack_take")?.finalize(key)?;
+        let full = temp_pack.append(hash, reader)?;
+        let length = temp_pack.records_size()? - start;
+        if full {
+            let pack = self.current_pack.take().ok_or("pack_take")?;
+            self.finalize_pack(pack, key)?;
         }
-        Ok(pack_id)
+        Ok((pack_id, length))
+    }
+
+    // The map keeps how much of the pack is records so that repack can tell how much of it is
+    // still live without reading it
+    fn finalize_pack(
+        &mut self,
+        pack: PackBuilder<Box<dyn Write + Send>>,
+        key: &key::MemKey,
+    ) -> Result<(), Box<dyn Error>> {
+        self.map.insert_pack(pack.id, pack.records_size()?)?;
+        self.uploaded += pack.finalize(key)?;
+        self.packs += 1;
+        Ok(())
     }
 
+    // Returns the count + total size of the packs that got uploaded
     pub(crate) fn finalize<W: Write>(
         mut self,
         map_content: W,
         key: &key::MemKey,
-    ) -> Result<(), Box<dyn Error>> {
+        policy: &compress::Policy,
+    ) -> Result<(u64, u64), Box<dyn Error>> {
         // Force an finalize if its not already finalized
-        if self.current_pack.is_some() {
-            self.current_pack.take().ok_or("pack_take")?.finalize(key)?;
+        if let Some(pack) = self.current_pack.take() {
+            self.finalize_pack(pack, key)?;
         }
 
         // Unload the sqlite file into remote as snapshot
-        self.map.unload(key, map_content)?;
-        Ok(())
+        self.map.unload(key, policy, map_content)?;
+        Ok((self.packs, self.uploaded))
     }
-
-    // TODO:
-    // 1. Implement a way to fetch by content_hash
-    // 2. returns packfiles involved + chunks involved
-    // 3. fetch needed packfiles + chunks and reassembly then stream it out
 }
 
 // This should do it in a streaming manner
@@ -171,38 +300,159 @@ impl<'a, B: Remote> ObjectFetch<'a, B> {
         }
     }
 
+    #[expect(clippy::type_complexity)]
     pub(crate) fn get_content(
         &mut self,
         key: &key::MemKey,
         hash: hash::Hash,
-    ) -> Result<Option<Box<dyn Read>>, Box<dyn Error>> {
-        // 1. map to get content -> packfile
-        match self.map.find_pack(hash) {
-            Ok(pack) => {
-                // 2. pack_cache to get packfile
-                if !self.cache.contains_key(&pack) {
-                    println!("Loading packfile: {pack:?}");
-
-                    let mut pack_read = self.remote.read(Typ::Pack, pack)?;
-                    let pack_file = PackOut::load(&mut pack_read, key)?;
-
-                    self.cache.insert(pack, pack_file);
-                }
-
-                // 3. extract content from packfile, return it
-                if self.cache.contains_key(&pack) {
-                    let data: Vec<u8> = self
-                        .cache
-                        .get(&pack)
-                        .ok_or("get_pack")?
-                        .find_hash(hash)
-                        .ok_or("hashclone")?;
-                    Ok(Some(Box::new(Cursor::new(data))))
-                } else {
-                    Ok(None)
-                }
+    ) -> Result<Option<(compress::Compression, Box<dyn Read + Send>)>, Box<dyn Error>> {
+        // 1. map to get content -> packfiles, one per part in part order
+        let packs = self.map.find_packs(hash)?;
+        if packs.is_empty() {
+            return Ok(None);
+        }
+        let comp = self.map.find_compression(hash)?;
+
+        // TODO: make this into a streaming read but for now reassemble the parts in ram
+        let mut data: Vec<u8> = Vec::new();
+        for pack in packs {
+            // 2. pack_cache to get packfile
+            if !self.cache.contains_key(&pack) {
+                info!("Loading packfile: {pack:?}");
+
+                let mut pack_read = self.remote.read(Typ::Pack, pack)?;
+                let pack_file = PackOut::load(&mut pack_read, key)?;
+
+                self.cache.insert(pack, pack_file);
             }
-            Err(e) => Err(e),
+
+            // 3. extract the part from packfile, append it
+            data.extend(
+                self.cache
+                    .get(&pack)
+                    .ok_or("get_pack")?
+                    .find_hash(hash)
+                    .ok_or("hashclone")?,
+            );
+        }
+        Ok(Some((comp, Box::new(Cursor::new(data)))))
+    }
+}
+
+#[cfg(all(test, feature = "sql"))]
+mod test_object_store {
+    use super::*;
+    use crate::remote::sql::SqlVFS;
+
+    #[test]
+    fn split_roundtrip() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+
+        let config = PackConfig {
+            target_size: 4 * 1024,
+            small_file: 3 * 1024,
+            max_size: 8 * 1024,
+        };
+        config.validate().unwrap();
+
+        let hash = key.gen_id();
+        let size = 3 * config.max_size + 5;
+        let data: Vec<u8> = (0..size).map(|x| u8::try_from(x % 251).unwrap()).collect();
+
+        let map = {
+            let mut cas = ObjectStore::new(&remote, &config).unwrap();
+            cas.append(
+                hash,
+                &key,
+                &mut &data[..],
+                size,
+                compress::Compression::Stored,
+            )
+            .unwrap();
+            cas.map
+        };
+        assert_eq!(map.find_packs(hash).unwrap().len(), 4);
+
+        let mut fetch = ObjectFetch::new(&mut remote, map);
+        let (comp, mut content) = fetch.get_content(&key, hash).unwrap().unwrap();
+        let mut out = vec![];
+        content.read_to_end(&mut out).unwrap();
+
+        assert_eq!(comp, compress::Compression::Stored);
+        assert_eq!(data, out);
+    }
+
+    #[test]
+    fn same_content_twice() {
+        let key = key::MemKey::new();
+        let remote = SqlVFS::new(None).unwrap();
+        let config = PackConfig::default();
+
+        let hash = key.gen_id();
+        let (data, stored) = (b"Hello World!", compress::Compression::Stored);
+        let mut cas = ObjectStore::new(&remote, &config).unwrap();
+        for _ in 0..2 {
+            cas.append(hash, &key, &mut &data[..], 12, stored).unwrap();
         }
+        assert_eq!(cas.map.find_packs(hash).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn resume_checkpoint() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let policy = compress::Policy::default();
+
+        let config = PackConfig {
+            target_size: 4 * 1024,
+            small_file: 3 * 1024,
+            max_size: 8 * 1024,
+        };
+        let checkpoint = |resumed| Checkpoint {
+            name: "C-test".to_owned(),
+            every: 1,
+            policy: policy.clone(),
+            resumed,
+            written: 0,
+        };
+
+        let (big, small) = (vec![1u8; 5 * 1024], vec![2u8; 1024]);
+        let (big_hash, small_hash) = (key.gen_id(), key.gen_id());
+        let stored = compress::Compression::Stored;
+
+        // Dies before the small pack is done, only the big pack made it into the checkpoint
+        {
+            let mut cas = ObjectStore::new(&remote, &config)
+                .unwrap()
+                .with_checkpoint(checkpoint(Map::new().unwrap()));
+            cas.append(big_hash, &key, &mut &big[..], 5 * 1024, stored)
+                .unwrap();
+            cas.append(small_hash, &key, &mut &small[..], 1024, stored)
+                .unwrap();
+        }
+        let resumed = Map::load(
+            &mut remote.read_filename(Typ::Checkpoint, "C-test").unwrap(),
+            &key,
+        )
+        .unwrap();
+        let big_packs = resumed.find_packs(big_hash).unwrap();
+        assert_eq!(big_packs.len(), 1);
+        assert!(resumed.find_packs(small_hash).unwrap().is_empty());
+
+        // The rerun reuses the big pack and only stores the small content again
+        let packs = remote.list_keys(Typ::Pack).unwrap().count();
+        let mut cas = ObjectStore::new(&remote, &config)
+            .unwrap()
+            .with_checkpoint(checkpoint(resumed));
+        cas.append(big_hash, &key, &mut &big[..], 5 * 1024, stored)
+            .unwrap();
+        cas.append(small_hash, &key, &mut &small[..], 1024, stored)
+            .unwrap();
+        assert_eq!(cas.map.find_packs(big_hash).unwrap(), big_packs);
+
+        let mut map = vec![];
+        assert_eq!(cas.finalize(&mut map, &key, &policy).unwrap().0, 1);
+        assert_eq!(remote.list_keys(Typ::Pack).unwrap().count(), packs + 1);
     }
 }
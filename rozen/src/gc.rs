@@ -0,0 +1,236 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use log::{debug, info};
+
+use crate::rcore::hash;
+use crate::rcore::key;
+
+use crate::remote::Remote;
+use crate::remote::Typ;
+
+use crate::snapshot::Keys;
+
+use crate::sql::Map;
+
+#[derive(Debug, Default)]
+pub struct Report {
+    // Unreferenced packs + maps that got deleted, or would be on a dry run
+    pub packs: Vec<String>,
+    pub maps: Vec<String>,
+
+    // Bytes freed (or to be freed) by deleting the above
+    pub bytes: u64,
+
+    // Unreferenced files left alone because they are younger than the grace period
+    pub recent: usize,
+
+    // Checkpoints of unfinished appends, their packs are left alone
+    pub checkpoints: usize,
+}
+
+// Garbage collection
+//  1. Load every remaining snapshot map and checkpoint, any pack either points to is live
+//  2. Packs that no map references and any map that has no index are garbage
+//  3. Garbage younger than the grace period is left alone, an append in progress writes its
+//     packs first and the index + map last so its packs looks unreferenced till then, or till
+//     its first checkpoint
+//
+// Checkpoints are never collected, the next append on the same host resumes from it and drops
+// it once done
+//
+// Packs that are only partly referenced are left alone, that is the repacker's job
+pub fn gc<B: Remote>(
+    key: &key::MemKey,
+    remote: &mut B,
+    snapshots: &[Keys],
+    grace: Duration,
+    dry_run: bool,
+) -> Result<Report, Box<dyn Error>> {
+    let mut report = Report::default();
+    let cutoff = SystemTime::now() - grace;
+
+    let mut live_packs = HashSet::new();
+    let mut live_maps = HashSet::new();
+    for snapshot in snapshots {
+        let map = Map::load(&mut remote.read_filename(Typ::Map, &snapshot.map)?, key)?;
+        for (_, _, pack, _) in map.chunks()? {
+            live_packs.insert(hash::to_hex(pack));
+        }
+        live_maps.insert(snapshot.map.as_str());
+    }
+    let (resumable, checkpoints) = checkpoint_packs(key, remote)?;
+    live_packs.extend(resumable.into_iter().map(hash::to_hex));
+    report.checkpoints = checkpoints;
+
+    let mut garbage = vec![];
+    for pack in remote.list_keys(Typ::Pack)? {
+        if !live_packs.contains(&pack) {
+            garbage.push((Typ::Pack, pack));
+        }
+    }
+    for map in remote.list_keys(Typ::Map)? {
+        if !live_maps.contains(map.as_str()) {
+            garbage.push((Typ::Map, map));
+        }
+    }
+
+    for (typ, filename) in garbage {
+        // Gone already, ie another gc got to it first
+        let Some(stat) = remote.stat_filename(typ, &filename)? else {
+            continue;
+        };
+
+        if stat.modified > cutoff {
+            debug!("RECENT: {typ} - {filename}");
+            report.recent += 1;
+            continue;
+        }
+
+        if dry_run {
+            info!("GARBAGE: {typ} - {filename}");
+        } else {
+            info!("DELETE: {typ} - {filename}");
+            remote.delete_filename(typ, &filename)?;
+        }
+
+        report.bytes += stat.size;
+        match typ {
+            Typ::Pack => report.packs.push(filename),
+            _ => report.maps.push(filename),
+        }
+    }
+
+    Ok(report)
+}
+
+// Packs that the checkpoints of unfinished appends point to along with the count of checkpoints,
+// they have to stay put till the append that left the checkpoint behind resumes
+pub(crate) fn checkpoint_packs<B: Remote>(
+    key: &key::MemKey,
+    remote: &mut B,
+) -> Result<(HashSet<hash::Hash>, usize), Box<dyn Error>> {
+    let (mut packs, mut checkpoints) = (HashSet::new(), 0);
+    for checkpoint in remote.list_keys(Typ::Checkpoint)? {
+        let map = Map::load(
+            &mut remote.read_filename(Typ::Checkpoint, &checkpoint)?,
+            key,
+        )?;
+        for (_, _, pack, _) in map.chunks()? {
+            packs.insert(pack);
+        }
+        checkpoints += 1;
+    }
+    Ok((packs, checkpoints))
+}
+
+#[cfg(all(test, feature = "sql"))]
+mod test_gc {
+    use super::*;
+    use std::io::Read as _;
+
+    use crate::rcore::compress;
+    use crate::remote::sql::SqlVFS;
+
+    #[test]
+    fn unreferenced() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let policy = compress::Policy::default();
+
+        let (live, dead) = (key.gen_id(), key.gen_id());
+        remote.write(Typ::Pack, live, &b"live"[..]).unwrap();
+        remote.write(Typ::Pack, dead, &b"dead"[..]).unwrap();
+
+        let map = Map::new().unwrap();
+        map.insert_chunk(key.gen_id(), 0, live, compress::Compression::Stored, 4)
+            .unwrap();
+        map.unload(
+            &key,
+            &policy,
+            remote.write_multi_filename(Typ::Map, "M-1").unwrap(),
+        )
+        .unwrap();
+
+        let stale = Map::new().unwrap();
+        stale
+            .unload(
+                &key,
+                &policy,
+                remote.write_multi_filename(Typ::Map, "M-2").unwrap(),
+            )
+            .unwrap();
+
+        let snapshots = [Keys {
+            index: "I-1".to_owned(),
+            map: "M-1".to_owned(),
+        }];
+
+        // Everything was just written so its all in the grace period
+        let report = gc(
+            &key,
+            &mut remote,
+            &snapshots,
+            Duration::from_hours(1),
+            false,
+        )
+        .unwrap();
+        assert_eq!(report.recent, 2);
+        assert!(report.packs.is_empty());
+
+        // Dry run reports but doesn't delete
+        let report = gc(&key, &mut remote, &snapshots, Duration::ZERO, true).unwrap();
+        assert_eq!(report.packs, vec![hash::to_hex(dead)]);
+        assert_eq!(report.maps, vec!["M-2".to_owned()]);
+        assert_eq!(remote.list_keys(Typ::Pack).unwrap().count(), 2);
+
+        let report = gc(&key, &mut remote, &snapshots, Duration::ZERO, false).unwrap();
+        assert_eq!(report.packs, vec![hash::to_hex(dead)]);
+        assert_eq!(remote.list_keys(Typ::Pack).unwrap().count(), 1);
+        assert_eq!(remote.list_keys(Typ::Map).unwrap().count(), 1);
+
+        let mut data = String::new();
+        remote
+            .read(Typ::Pack, live)
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "live");
+    }
+
+    #[test]
+    fn checkpoint_packs() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let policy = compress::Policy::default();
+
+        let (resumable, dead) = (key.gen_id(), key.gen_id());
+        remote
+            .write(Typ::Pack, resumable, &b"resumable"[..])
+            .unwrap();
+        remote.write(Typ::Pack, dead, &b"dead"[..]).unwrap();
+
+        let checkpoint = Map::new().unwrap();
+        checkpoint
+            .insert_chunk(key.gen_id(), 0, resumable, compress::Compression::Stored, 9)
+            .unwrap();
+        checkpoint
+            .unload(
+                &key,
+                &policy,
+                remote
+                    .write_multi_filename(Typ::Checkpoint, "C-test")
+                    .unwrap(),
+            )
+            .unwrap();
+
+        // An unfinished append has no snapshot yet but its checkpoint keeps its packs alive
+        let report = gc(&key, &mut remote, &[], Duration::ZERO, false).unwrap();
+        assert_eq!(report.packs, vec![hash::to_hex(dead)]);
+        assert_eq!(report.checkpoints, 1);
+        assert!(remote.stat(Typ::Pack, resumable).unwrap().is_some());
+        assert_eq!(remote.list_keys(Typ::Checkpoint).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,520 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Seek as _, SeekFrom, copy};
+use std::os::unix::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender};
+
+use log::{info, warn};
+use tempfile::SpooledTempFile;
+
+use crate::rcore::compress;
+use crate::rcore::crypto;
+use crate::rcore::hash;
+use crate::rcore::key;
+use crate::rcore::meta;
+
+use crate::snapshot::Change;
+use crate::snapshot::ErrorPolicy;
+use crate::snapshot::Plan;
+use crate::snapshot::Skipped;
+
+// The stages of the backup pipeline, each stage is connected to the next by a bounded channel
+//
+//  walker -> hash+compress+encrypt readers (pool) -> pack writer
+//     \                                               /
+//      `------ dirs, symlinks, specials (index only) -'
+//
+// The pack writer is single threaded since it owns the index + map sqlite dbs and the current
+// packfile, it lives in `snapshot::append`
+//
+// Only the readers run in parallel, the walker is a single thread going through the sources in
+// order
+
+// Spool up to 8Mb of compressed+encrypted data in ram before spilling it to disk
+pub(crate) const SPOOL_SIZE: usize = 8 * 1024 * 1024;
+
+// Reads of a file that keeps changing while being read before settling for the last one
+const READ_ATTEMPTS: usize = 3;
+
+pub(crate) type IResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+// Fingerprint + content + extents of the files in the parent snapshot, keyed by path
+pub(crate) type Parent = HashMap<String, (meta::Fingerprint, hash::Hash, Vec<meta::Extent>)>;
+
+// Walked entry, tagged with the index of the source it came from
+pub(crate) struct Walked {
+    source: usize,
+    entry: ignore::DirEntry,
+}
+
+// What every stage carries along for the index
+pub(crate) struct Entry {
+    pub path: PathBuf,
+    pub meta: fs::Metadata,
+    pub source: usize,
+    pub extents: Vec<meta::Extent>,
+
+    // Kept changing while being read, the content is whatever the last read saw
+    pub unstable: bool,
+}
+
+// Content read in a single pass, hashed + compressed + encrypted and spooled
+struct Sealed {
+    hash: hash::Hash,
+    size: u64,
+    stored: u64,
+    comp: compress::Compression,
+    data: SpooledTempFile,
+
+    // As of the start of the read, reading it bumps the atime
+    meta: fs::Metadata,
+    extents: Vec<meta::Extent>,
+    unstable: bool,
+}
+
+// Work for the pack writer
+pub(crate) struct Ingest {
+    pub entry: Entry,
+    pub kind: Kind,
+}
+
+pub(crate) enum Kind {
+    // Content is already claimed by another file, only needs to go into the index
+    Index {
+        hash: hash::Hash,
+        size: u64,
+    },
+
+    // Unchanged since the parent snapshot, the content gets carried over from its map
+    Unchanged {
+        hash: hash::Hash,
+    },
+
+    // Directories, symlinks and special files, there is no content only the entry
+    Special {
+        target: Option<PathBuf>,
+    },
+
+    // New content, compressed + encrypted and spooled, ready to go into a pack
+    Blob {
+        hash: hash::Hash,
+        size: u64,
+        stored: u64,
+        comp: compress::Compression,
+        data: SpooledTempFile,
+    },
+}
+
+// The next stage went away, only ever an error when that stage bailed out with the actual error
+#[derive(Debug)]
+pub(crate) struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ingest pipeline closed")
+    }
+}
+
+impl Error for Closed {}
+
+// Receiving end shared between a pool of workers
+pub(crate) struct SharedRx<T>(Mutex<Option<Receiver<T>>>);
+
+impl<T> SharedRx<T> {
+    pub(crate) fn new(rx: Receiver<T>) -> Self {
+        Self(Mutex::new(Some(rx)))
+    }
+
+    // None once the sending side is done or it got closed, a poisoned lock means another worker
+    // panicked which gets reported when that worker is joined
+    fn recv(&self) -> Option<T> {
+        self.0.lock().ok()?.as_ref()?.recv().ok()
+    }
+
+    // Stops every worker at their next recv and hangs up on the sending side, for when a worker
+    // bails out. The sender must not be blocked on the lock so it needs to be gone already if
+    // the sending side is the one closing it
+    pub(crate) fn close(&self) {
+        if let Ok(mut rx) = self.0.lock() {
+            drop(rx.take());
+        }
+    }
+}
+
+// Entries that don't make it into the snapshot, shared between the stages
+pub(crate) struct Skips {
+    policy: ErrorPolicy,
+    count: AtomicU64,
+    errors: Mutex<Vec<Skipped>>,
+}
+
+impl Skips {
+    pub(crate) fn new(policy: ErrorPolicy) -> Self {
+        Self {
+            policy,
+            count: AtomicU64::new(0),
+            errors: Mutex::new(vec![]),
+        }
+    }
+
+    // Left out on purpose such as sockets when special files are not recorded
+    fn skip(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Records the error and carries on, unless the policy is to fail the snapshot
+    pub(crate) fn error<E: fmt::Display>(&self, path: &Path, err: E) -> IResult<()> {
+        warn!("ERRR: {}: {err}", path.display());
+        match self.policy {
+            ErrorPolicy::Abort => Err(format!("{}: {err}", path.display()).into()),
+            ErrorPolicy::Skip => {
+                self.skip();
+                self.errors
+                    .lock()
+                    .map_err(|_| "skips lock poisoned")?
+                    .push(Skipped {
+                        path: format!("{}", path.display()),
+                        reason: err.to_string(),
+                    });
+                Ok(())
+            }
+        }
+    }
+
+    // Count of every entry left out, along with the ones left out because of an error
+    pub(crate) fn into_inner(self) -> IResult<(u64, Vec<Skipped>)> {
+        let errors = self
+            .errors
+            .into_inner()
+            .map_err(|_| "skips lock poisoned")?;
+        Ok((self.count.into_inner(), errors))
+    }
+}
+
+fn send<T>(tx: &SyncSender<T>, item: T) -> IResult<()> {
+    // The receiver only goes away if the downstream stage bailed out, it reports the error
+    tx.send(item).map_err(|_| Closed.into())
+}
+
+// Walks every root of every source in order, tracking the entries that don't get backed up
+pub(crate) fn walk(
+    sources: Vec<Vec<ignore::Walk>>,
+    special_files: bool,
+    skips: &Skips,
+    tx: &SyncSender<Walked>,
+    write_tx: &SyncSender<Ingest>,
+) -> IResult<()> {
+    for (source, walkers) in sources.into_iter().enumerate() {
+        for entry in walkers.into_iter().flatten() {
+            match entry {
+                Ok(e) => match e.file_type() {
+                    None => {
+                        info!("NONE: {}", e.path().display());
+                        skips.skip();
+                    }
+                    Some(ft) if ft.is_file() => send(tx, Walked { source, entry: e })?,
+                    Some(ft) if ft.is_dir() => send_special(write_tx, skips, source, e, None)?,
+                    Some(ft) if ft.is_symlink() => match fs::read_link(e.path()) {
+                        Ok(target) => send_special(write_tx, skips, source, e, Some(target))?,
+                        Err(err) => skips.error(e.path(), err)?,
+                    },
+                    Some(_) if special_files => send_special(write_tx, skips, source, e, None)?,
+                    Some(_) => {
+                        info!("SKIP: {}", e.path().display());
+                        skips.skip();
+                    }
+                },
+                Err(err) => skips.error(error_path(&err).unwrap_or(Path::new("")), &err)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+// The path the walk failed on, if the error knows it
+fn error_path(err: &ignore::Error) -> Option<&Path> {
+    match err {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::Loop { child, .. } => Some(child),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            error_path(err)
+        }
+        _ => None,
+    }
+}
+
+fn send_special(
+    tx: &SyncSender<Ingest>,
+    skips: &Skips,
+    source: usize,
+    e: ignore::DirEntry,
+    target: Option<PathBuf>,
+) -> IResult<()> {
+    info!("SPEC: {}", e.path().display());
+    let meta = match e.metadata() {
+        Ok(meta) => meta,
+        Err(err) => return skips.error(e.path(), err),
+    };
+    send(
+        tx,
+        Ingest {
+            entry: Entry {
+                meta,
+                path: e.into_path(),
+                source,
+                extents: vec![],
+                unstable: false,
+            },
+            kind: Kind::Special { target },
+        },
+    )
+}
+
+// Reads every file once, hashing it while it gets compressed + encrypted into a spool, then claims
+// the content. Content that was claimed already only needs indexing and its spool gets dropped
+pub(crate) fn hash(
+    key: &key::MemKey,
+    policy: &compress::Policy,
+    claimed: &Mutex<HashSet<hash::Hash>>,
+    parent: Option<&Parent>,
+    skips: &Skips,
+    rx: &SharedRx<Walked>,
+    write_tx: &SyncSender<Ingest>,
+) -> IResult<()> {
+    while let Some(Walked { source, entry: e }) = rx.recv() {
+        let meta = match e.metadata() {
+            Ok(meta) => meta,
+            Err(err) => {
+                skips.error(e.path(), err)?;
+                continue;
+            }
+        };
+
+        let unchanged = parent
+            .and_then(|p| p.get(&format!("{}", e.path().display())))
+            .filter(|(fingerprint, ..)| *fingerprint == meta::Fingerprint::from_fs(&meta));
+
+        if let Some((_, content_hash, extents)) = unchanged {
+            info!("SAME: {}", e.path().display());
+
+            // Claim it so that any other file with this content only needs indexing
+            claimed
+                .lock()
+                .map_err(|_| "claimed lock poisoned")?
+                .insert(*content_hash);
+
+            let entry = Entry {
+                path: e.into_path(),
+                meta,
+                source,
+                extents: extents.clone(),
+                unstable: false,
+            };
+            let kind = Kind::Unchanged {
+                hash: *content_hash,
+            };
+            send(write_tx, Ingest { entry, kind })?;
+            continue;
+        }
+
+        info!("HASH: {}", e.path().display());
+        let sealed = match read(key, policy, e.path()) {
+            Ok(sealed) => sealed,
+            Err(err) => {
+                skips.error(e.path(), err)?;
+                continue;
+            }
+        };
+
+        // Only the first file with this content gets stored, the rest only needs indexing
+        let is_new = claimed
+            .lock()
+            .map_err(|_| "claimed lock poisoned")?
+            .insert(sealed.hash);
+
+        let entry = Entry {
+            path: e.into_path(),
+            meta: sealed.meta,
+            source,
+            extents: sealed.extents,
+            unstable: sealed.unstable,
+        };
+        let kind = if is_new {
+            Kind::Blob {
+                hash: sealed.hash,
+                size: sealed.size,
+                stored: sealed.stored,
+                comp: sealed.comp,
+                data: sealed.data,
+            }
+        } else {
+            Kind::Index {
+                hash: sealed.hash,
+                size: sealed.size,
+            }
+        };
+        send(write_tx, Ingest { entry, kind })?;
+    }
+    Ok(())
+}
+
+// Dry run counterpart of `hash`, only the metadata gets compared against the parent so none of
+// the content gets read
+pub(crate) fn plan(
+    parent: Option<&Parent>,
+    skips: &Skips,
+    rx: Receiver<Walked>,
+    plan: &mut Plan,
+) -> IResult<()> {
+    let mut links = HashSet::new();
+    for Walked { entry: e, .. } in rx {
+        let meta = match e.metadata() {
+            Ok(meta) => meta,
+            Err(err) => {
+                skips.error(e.path(), err)?;
+                continue;
+            }
+        };
+        let change = match parent.and_then(|p| p.get(&format!("{}", e.path().display()))) {
+            Some((fingerprint, ..)) if *fingerprint == meta::Fingerprint::from_fs(&meta) => {
+                plan.files_unchanged += 1;
+                continue;
+            }
+            Some(_) => Change::Modified,
+            None => Change::Added,
+        };
+
+        // Every link to the same inode holds the same content
+        if meta.nlink() < 2 || links.insert((meta.dev(), meta.ino())) {
+            plan.new_bytes += meta.len();
+        }
+        plan.files.push((change, e.into_path(), meta.len()));
+    }
+    Ok(())
+}
+
+// Hashes + seals the file in one read so the stored content always matches its hash. Only the
+// data regions gets read so the holes of a sparse file never makes it into storage. The file
+// changed while being read if its fingerprint differs after the read or the read didn't see all
+// of it, then it gets read again a couple of times before settling for the last read
+fn read(key: &key::MemKey, policy: &compress::Policy, path: &Path) -> IResult<Sealed> {
+    let mut attempt = 1;
+    loop {
+        let mut file = File::open(path)?;
+        let before = file.metadata()?;
+
+        // A dense file gets read to the end, it may have grown or lie about its size like procfs
+        let extents = meta::read_extents(&file, before.len())?;
+        let (regions, data_len) = match &extents {
+            Some(extents) => (&extents[..], extents.iter().map(|(_, len)| len).sum()),
+            None => (&[(0, u64::MAX)][..], before.len()),
+        };
+
+        let mut reader = hash::HashReader::new(key, meta::ExtentReader::new(&mut file, regions));
+        let (comp, stored, data) = seal(key, policy, path, data_len, &mut reader)?;
+        let (hash, size) = reader.finalize();
+
+        let after = file.metadata()?;
+        let unstable = size != data_len
+            || meta::Fingerprint::from_fs(&before) != meta::Fingerprint::from_fs(&after);
+
+        // Without a hole in front of some data the content + size is enough to lay it out again,
+        // any other layout gets recorded and is part of the hash so it only dedups with itself
+        let extents = extents
+            .filter(|extents| !matches!(extents[..], [] | [(0, _)]))
+            .unwrap_or_default();
+        let hash = hash::with_extents(key, hash, &extents);
+
+        if !unstable || attempt == READ_ATTEMPTS {
+            if unstable {
+                warn!("CHNG: {}", path.display());
+            }
+            return Ok(Sealed {
+                hash,
+                size,
+                stored,
+                comp,
+                data,
+                meta: before,
+                extents,
+                unstable,
+            });
+        }
+
+        info!("RTRY: {}", path.display());
+        attempt += 1;
+    }
+}
+
+// Compresses + encrypts the content into a spool, returns how it got compressed along with the
+// size of the spooled data
+pub(crate) fn seal<R: Read>(
+    key: &key::MemKey,
+    policy: &compress::Policy,
+    path: &Path,
+    size: u64,
+    reader: R,
+) -> IResult<(compress::Compression, u64, SpooledTempFile)> {
+    let (comp, reader) = policy.compress(path, size, reader)?;
+    let mut enc = crypto::encrypt(key, reader)?;
+
+    let mut data = tempfile::spooled_tempfile(SPOOL_SIZE);
+    let stored = copy(&mut enc, &mut data)?;
+    data.seek(SeekFrom::Start(0))?;
+    Ok((comp, stored, data))
+}
+
+#[cfg(test)]
+mod test_ingest {
+    use super::*;
+
+    // What got stored decrypts + decompresses back into content matching the hash
+    fn unseal(key: &key::MemKey, sealed: Sealed) -> (hash::Hash, Vec<u8>) {
+        let mut dec = crypto::decrypt(key, sealed.data).unwrap();
+        let mut und = compress::decompress(sealed.comp, &mut dec).unwrap();
+        let mut content = vec![];
+        und.read_to_end(&mut content).unwrap();
+        (hash::hash(key, &mut &content[..]).unwrap(), content)
+    }
+
+    #[test]
+    fn single_read() {
+        let key = key::MemKey::new();
+        let policy = compress::Policy::default();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), b"Hello World!").unwrap();
+
+        let sealed = read(&key, &policy, file.path()).unwrap();
+        assert!(!sealed.unstable);
+        assert_eq!(sealed.size, 12);
+
+        let hash = sealed.hash;
+        assert_eq!(unseal(&key, sealed), (hash, b"Hello World!".to_vec()));
+    }
+
+    #[test]
+    fn changed_while_read() {
+        let key = key::MemKey::new();
+        let policy = compress::Policy::default();
+
+        // Claims to be empty but there is always something to read, so every read looks like the
+        // file changed underneath it
+        let path = Path::new("/proc/self/stat");
+        let sealed = read(&key, &policy, path).unwrap();
+        assert!(sealed.unstable);
+        assert_eq!(sealed.meta.len(), 0);
+        assert!(sealed.size > 0);
+
+        // Even then what got stored is what got hashed
+        let (hash, size) = (sealed.hash, sealed.size);
+        let (stored_hash, content) = unseal(&key, sealed);
+        assert_eq!(stored_hash, hash);
+        assert_eq!(content.len() as u64, size);
+    }
+}
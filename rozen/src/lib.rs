@@ -1,7 +1,10 @@
 mod cas;
+pub mod gc;
+mod ingest;
 mod log;
 pub mod rarc;
 pub mod rcore;
 pub mod remote;
+pub mod repack;
 pub mod snapshot;
 mod sql;
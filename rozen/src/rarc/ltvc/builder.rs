@@ -69,6 +69,10 @@ impl<W: Write> LtvcBuilder<W> {
         self.write(*b"SHDR", &[])
     }
 
+    pub(super) fn write_smta(&mut self) -> Result<usize, Error> {
+        self.write(*b"SMTA", &[])
+    }
+
     // TODO: may be worth moving compression/encryption? to ensure that only
     // compressed+encrypted data arrives here, but also the management of those
     // might be better else where cos there might be multi-threading concerns
@@ -111,6 +111,25 @@ impl<W: Write> LtvcIndexing<W> {
         Ok(())
     }
 
+    pub fn append_snapshot_meta<R: Read>(
+        &mut self,
+        hash: hash::Hash,
+        reader: &mut R,
+    ) -> Result<(), Box<dyn Error>> {
+        let m_idx = self.idx;
+
+        self.idx += self.inner.write_smta()?;
+        self.idx += self.inner.write_edat(reader)?;
+
+        self.h_idx.push(HeaderIdx {
+            typ: *b"SMTA",
+            hash,
+            start_idx: m_idx,
+            length: self.idx - m_idx,
+        });
+        Ok(())
+    }
+
     pub fn append_pack_index<R: Read>(
         &mut self,
         hash: hash::Hash,
@@ -130,7 +149,12 @@ impl<W: Write> LtvcIndexing<W> {
         Ok(())
     }
 
-    pub fn finalize(mut self, append_aidx: bool, key: &key::MemKey) -> Result<(), Box<dyn Error>> {
+    // Returns the total size of the archive
+    pub fn finalize(
+        mut self,
+        append_aidx: bool,
+        key: &key::MemKey,
+    ) -> Result<usize, Box<dyn Error>> {
         if append_aidx {
             let a_idx = self.idx;
 
@@ -153,7 +177,7 @@ impl<W: Write> LtvcIndexing<W> {
 
         // Flush to signal to the backend that its done
         self.inner.into_inner().flush()?;
-        Ok(())
+        Ok(self.idx)
     }
 }
 
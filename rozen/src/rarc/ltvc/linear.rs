@@ -12,6 +12,7 @@ pub enum Header {
     Fhdr { hash: hash::Hash },
     Aidx,
     Shdr,
+    Smta,
     Pidx,
 }
 
@@ -58,7 +59,7 @@ impl<R: Read> Iterator for LtvcLinear<R> {
                 }
 
                 // Assert that the Header state follows Ahdr or Edat, where
-                // header state is: Fhdr/Aidx/Shdr/Pidx
+                // header state is: Fhdr/Aidx/Shdr/Smta/Pidx
                 (Spo::Ahdr | Spo::Edat, Some(Ok(LtvcEntry::Fhdr { hash }))) => {
                     debug!("FHDR <{hash:?}>");
                     self.state = Spo::Header(Header::Fhdr { hash });
@@ -74,6 +75,11 @@ impl<R: Read> Iterator for LtvcLinear<R> {
                     self.state = Spo::Header(Header::Shdr);
                 }
 
+                (Spo::Ahdr | Spo::Edat, Some(Ok(LtvcEntry::Smta))) => {
+                    debug!("SMTA");
+                    self.state = Spo::Header(Header::Smta);
+                }
+
                 (Spo::Ahdr | Spo::Edat, Some(Ok(LtvcEntry::Pidx))) => {
                     debug!("PIDX");
                     self.state = Spo::Header(Header::Pidx);
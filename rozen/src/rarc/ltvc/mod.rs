@@ -41,6 +41,7 @@
 //! | AHDR       | Archive Header    | The first chunk, holds archive wide metadata |
 //! | FHDR       | File Header       | Holds per-file metadata |
 //! | SHDR       | Snapshot Header   | Holds snapshot metadata |
+//! | SMTA       | Snapshot Record   | Holds the descriptive record of a snapshot |
 //! | AIDX       | Archive Index     | Offset+length index of all records in the archive |
 //! | PIDX       | Pack Index        | Chunk to Packfile index |
 //! | EDAT       | Encrypted Data    | Encrypted blobs. `AIDX/FHDR` before defines the content |
@@ -77,6 +78,14 @@
 //!
 //! There is currently no data held within the value field of this chunk.
 //!
+//! ## SMTA
+//!
+//! Like `SHDR` this defines what the `EDAT` that follows contains. This is specifically for the
+//! descriptive record of a snapshot (host, user, sources, times, tags, etc). It goes ahead of
+//! the `SHDR` so that a listing of snapshots only needs to read the start of each one.
+//!
+//! There is currently no data held within the value field of this chunk.
+//!
 //! ## AIDX
 //!
 //! This like the `FHDR` chunk also defines what the content inside the `EDAT` that follows. This
@@ -96,8 +105,8 @@
 //!
 //! ## EDAT
 //!
-//! Encrypted data chunk. This must be preceeded by an; `FHDR`, `SHDR`, `AIDX`, or `PIDX`  at this
-//! point in time. This contains the encrypted and compressed datastream.
+//! Encrypted data chunk. This must be preceeded by an; `FHDR`, `SHDR`, `SMTA`, `AIDX`, or `PIDX`
+//! at this point in time. This contains the encrypted and compressed datastream.
 //!
 //! There must be 1 or more chunk to hold the entire datastream. To support the streaming usecase
 //! the content of each `EDAT` is appended to the preceeding one. The LTVC reader is allowed to
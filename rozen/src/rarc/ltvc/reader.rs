@@ -37,6 +37,7 @@ pub(super) enum LtvcEntry<R: Read> {
         hash: Hash,
     },
     Shdr,
+    Smta,
     Aidx,
     Pidx,
     Edat {
@@ -140,6 +141,7 @@ impl<R: Read> Iterator for LtvcReader<R> {
                         }))
                     }
                     b"SHDR" => Some(Ok(LtvcEntry::Shdr)),
+                    b"SMTA" => Some(Ok(LtvcEntry::Smta)),
                     b"AIDX" => Some(Ok(LtvcEntry::Aidx)),
                     b"PIDX" => Some(Ok(LtvcEntry::Pidx)),
                     b"EDAT" => {
@@ -244,6 +246,24 @@ mod test_ltvc_iterator {
         assert!(reader.next().is_none());
     }
 
+    #[test]
+    fn one_smta() {
+        // Write to the stream
+        let data = Cursor::new(Vec::new());
+        let mut builder = LtvcBuilder::new(data);
+        builder.write_smta().unwrap();
+
+        // Reset stream
+        let mut data = builder.into_inner();
+        data.seek(SeekFrom::Start(0)).unwrap();
+
+        // Read back and assert stuff
+        let mut reader = LtvcReader::new(data);
+
+        assert_eq!(LtvcEntry::Smta, reader.next().unwrap().unwrap());
+        assert!(reader.next().is_none());
+    }
+
     #[test]
     fn one_aidx() {
         // Write to the stream
@@ -1,7 +1,10 @@
 use binrw::BinRead as _;
+use log::debug;
+use serde::Deserialize;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::error::Error;
-use std::io::{Cursor, Read, Write, copy};
+use std::io::{BufRead, Cursor, Read, Write, copy};
 use zstd::stream::read::Decoder;
 
 use crate::rcore::crypto;
@@ -14,26 +17,89 @@ use crate::rarc::ltvc::linear::EdatStream;
 use crate::rarc::ltvc::linear::Header;
 use crate::rarc::ltvc::linear::LtvcLinear;
 
-// TODO: set to 1gb at some point
-const PACK_SIZE: usize = 4 * 1024;
+// Largest object S3 will take (5TiB)
+const S3_MAX_OBJECT: u64 = 5 * 1024 * 1024 * 1024 * 1024;
+
+// Pack sizing, this is a tradeoff between the number of objects (S3 request costs) vs how much
+// data needs to be fetched to restore a single file out of a pack
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct PackConfig {
+    // Once a pack of small files reaches this size it gets finalized
+    pub target_size: u64,
+
+    // Files larger than this gets their own pack(s) instead of being packed together
+    pub small_file: u64,
+
+    // Max size of a single part of a file before it gets split into the next pack. Repacking
+    // loads each pack it rewrites whole into memory, so this is also its memory bound
+    pub max_size: u64,
+}
+
+impl Default for PackConfig {
+    fn default() -> Self {
+        Self {
+            target_size: 1024 * 1024 * 1024,
+            small_file: 16 * 1024 * 1024,
+            max_size: 4 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl PackConfig {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.target_size == 0 || self.small_file == 0 || self.max_size == 0 {
+            return Err("Pack sizes must be non-zero".into());
+        }
+        if self.small_file > self.target_size {
+            return Err(format!(
+                "Small file cutoff ({}) is larger than the pack target size ({})",
+                self.small_file, self.target_size
+            )
+            .into());
+        }
+        // A pack is only finalized once it is past the target, so the last small file appended
+        // can carry it up to target + small file
+        if self.target_size.saturating_add(self.small_file) > self.max_size {
+            return Err(format!(
+                "Pack target size ({}) plus the small file cutoff ({}) is larger than the max pack size ({})",
+                self.target_size, self.small_file, self.max_size
+            )
+            .into());
+        }
+        if self.max_size > S3_MAX_OBJECT {
+            return Err(format!(
+                "Max pack size ({}) is larger than the S3 object limit ({S3_MAX_OBJECT})",
+                self.max_size
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
 
 pub struct PackBuilder<W: Write> {
     pub id: hash::Hash,
     inner: LtvcIndexing<W>,
+    target_size: u64,
+
+    // Size of the archive header ahead of the records
+    header: usize,
 }
 
 // TODO: implement drop to call finalize
-// TODO: implement some form of split for too large files here
-//  * Allow the archive to go over x% (or) allow chunks/file to go over xMB
-//  * if they go over, they get split. All parts start with 'part count 0'
-//  * If there's more then it becomes <hash>.p1....???
-//  * Need to figure out a good way to handle the indexing or might just delegate
-//  to higher layer and just index on 'hash + part -> idx + len'
+//
+// Too large files are split by the higher layer via `append_part`
+//  * Each part goes into its own pack, all parts start with 'part count 0'
+//  * Indexing is delegated to the higher layer which index on 'hash + part -> pack'
 impl<W: Write> PackBuilder<W> {
-    pub fn new(id: hash::Hash, writer: W) -> Result<Self, Box<dyn Error>> {
+    pub fn new(id: hash::Hash, writer: W, target_size: u64) -> Result<Self, Box<dyn Error>> {
+        let inner = LtvcIndexing::new(writer)?;
         Ok(Self {
             id,
-            inner: LtvcIndexing::new(writer)?,
+            header: inner.get_size(),
+            inner,
+            target_size,
         })
     }
 
@@ -43,14 +109,33 @@ impl<W: Write> PackBuilder<W> {
         reader: &mut R,
     ) -> Result<bool, Box<dyn Error>> {
         self.inner.append_file(hash, reader)?;
-        Ok(self.inner.get_size() >= PACK_SIZE)
+        Ok(u64::try_from(self.inner.get_size())? >= self.target_size)
+    }
+
+    // Appends up to `limit` bytes of the reader as one part of the file, returns true if there
+    // is still data left in the reader that needs to go into the next part
+    pub fn append_part<R: BufRead>(
+        &mut self,
+        hash: hash::Hash,
+        reader: &mut R,
+        limit: u64,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.inner
+            .append_file(hash, &mut reader.by_ref().take(limit))?;
+        Ok(!reader.fill_buf()?.is_empty())
+    }
+
+    // Bytes of the records appended so far, the difference across an append is the length of
+    // its record
+    pub fn records_size(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::try_from(self.inner.get_size() - self.header)?)
     }
 
     // TODO: should hash+hmac various data bits in a packfile
     // Store the hmac hash of the packfile in packfile + snapshot itself.
-    pub fn finalize(self, key: &key::MemKey) -> Result<(), Box<dyn Error>> {
-        self.inner.finalize(true, key)?;
-        Ok(())
+    // Returns the size of the finished pack
+    pub fn finalize(self, key: &key::MemKey) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::try_from(self.inner.finalize(true, key)?)?)
     }
 }
 
@@ -59,7 +144,7 @@ impl<W: Write> PackBuilder<W> {
 // TODO: make it into an actual streaming/indexing packout but for now just buffer in ram
 pub struct PackOut {
     idx: HashMap<hash::Hash, Vec<u8>>,
-    _idx: Vec<HeaderIdx>,
+    chunk_idx: Vec<HeaderIdx>,
 }
 
 impl PackOut {
@@ -71,7 +156,7 @@ impl PackOut {
         for EdatStream { header, mut data } in ltvc {
             match header {
                 Header::Fhdr { hash } => {
-                    println!("FHDR - EDAT");
+                    debug!("FHDR - EDAT");
 
                     let mut out_data = vec![];
                     copy(&mut data, &mut out_data)?;
@@ -79,7 +164,7 @@ impl PackOut {
                     idx.insert(hash, out_data);
                 }
                 Header::Aidx => {
-                    println!("AIDX - EDAT");
+                    debug!("AIDX - EDAT");
                     let mut idx_buf: Vec<u8> = Vec::new();
                     let mut dec = crypto::decrypt(key, &mut data)?;
                     let mut und = Decoder::new(&mut dec)?;
@@ -96,7 +181,7 @@ impl PackOut {
                         },
                     )?;
 
-                    println!("AIDX - EDAT - length: {}", chunk_idx.len());
+                    debug!("AIDX - EDAT - length: {}", chunk_idx.len());
                 }
 
                 // Skip header we don't care for
@@ -104,13 +189,67 @@ impl PackOut {
             }
         }
 
-        Ok(Self {
-            idx,
-            _idx: chunk_idx,
-        })
+        Ok(Self { idx, chunk_idx })
     }
 
     pub fn find_hash(&self, hash: hash::Hash) -> Option<Vec<u8>> {
         self.idx.get(&hash).cloned()
     }
+
+    // Hash + on disk length (FHDR + EDATs) of each file record in the pack
+    pub fn records(&self) -> impl Iterator<Item = (hash::Hash, usize)> + '_ {
+        self.chunk_idx
+            .iter()
+            .filter(|h| &h.typ == b"FHDR")
+            .map(|h| (h.hash, h.length))
+    }
+}
+
+#[cfg(test)]
+mod test_pack_config {
+    use super::*;
+
+    #[test]
+    fn default_valid() {
+        PackConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn small_file_over_target() {
+        let config = PackConfig {
+            target_size: 1024,
+            small_file: 2048,
+            max_size: 4096,
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn target_over_max() {
+        let config = PackConfig {
+            target_size: 8192,
+            small_file: 1024,
+            max_size: 4096,
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn target_plus_small_over_max() {
+        let config = PackConfig {
+            target_size: 4096,
+            small_file: 1024,
+            max_size: 4096,
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn zero_size() {
+        let config = PackConfig {
+            target_size: 0,
+            ..PackConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
 }
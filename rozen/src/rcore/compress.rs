@@ -0,0 +1,255 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+use zstd::stream::read::Decoder;
+use zstd::stream::read::Encoder;
+
+// Fast level used for the trial compression of the sample
+const TRIAL_LEVEL: i32 = 1;
+
+// How the blob was stored, this gets recorded per blob so that the fetch side knows if
+// it needs to decompress the blob or not
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Stored,
+    Zstd,
+}
+
+impl Compression {
+    pub fn to_flag(self) -> u8 {
+        match self {
+            Self::Stored => 0,
+            Self::Zstd => 1,
+        }
+    }
+
+    pub fn from_flag(flag: u8) -> Result<Self, String> {
+        match flag {
+            0 => Ok(Self::Stored),
+            1 => Ok(Self::Zstd),
+            x => Err(format!("Unknown compression flag: {x}")),
+        }
+    }
+}
+
+// Compression policy
+// 1. Tiny files aren't worth the zstd frame overhead, store them as it is
+// 2. Known already compressed formats (media, archives) gets stored as it is
+// 3. Everything else gets a fast trial compression of the first sample bytes, if it doesn't
+//    shrink enough its most likely incompressible so store it as it is
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Policy {
+    // zstd level for the data that do get compressed
+    pub level: i32,
+
+    // Files smaller than this are always stored
+    pub min_size: u64,
+
+    // Extensions (case insensitive) of files that are always stored
+    pub skip_extensions: Vec<String>,
+
+    // Size of the sample to trial compress
+    pub trial_size: usize,
+
+    // Max compressed/original ratio of the trial before we give up and store it
+    pub trial_ratio: f64,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            level: 21,
+            min_size: 128,
+            skip_extensions: [
+                "7z", "avif", "br", "bz2", "flac", "gif", "gz", "heic", "jpeg", "jpg", "lz4",
+                "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "opus", "png", "rar", "tgz",
+                "webm", "webp", "xz", "zip", "zst",
+            ]
+            .into_iter()
+            .map(str::to_owned)
+            .collect(),
+            trial_size: 64 * 1024,
+            trial_ratio: 0.95,
+        }
+    }
+}
+
+impl Policy {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let levels = zstd::compression_level_range();
+        if !levels.contains(&self.level) {
+            return Err(format!(
+                "Compression level ({}) is outside of zstd's range ({}..={})",
+                self.level,
+                levels.start(),
+                levels.end()
+            )
+            .into());
+        }
+        if !(0.0..=1.0).contains(&self.trial_ratio) {
+            return Err(format!(
+                "Trial compression ratio ({}) is outside of 0..=1",
+                self.trial_ratio
+            )
+            .into());
+        }
+        if self.trial_size == 0 {
+            return Err("Trial compression sample size must be non-zero".into());
+        }
+        Ok(())
+    }
+
+    // Decide how to store the reader, returns the decision plus a reader that yields the data
+    // in that form, the sample used for the trial is spliced back in front of the reader
+    pub fn compress<'a, R: Read + 'a>(
+        &self,
+        path: &Path,
+        size: u64,
+        mut reader: R,
+    ) -> std::io::Result<(Compression, Box<dyn Read + 'a>)> {
+        if size < self.min_size || self.is_skipped(path) {
+            return Ok((Compression::Stored, Box::new(reader)));
+        }
+
+        let mut sample = Vec::with_capacity(self.trial_size);
+        reader
+            .by_ref()
+            .take(self.trial_size as u64)
+            .read_to_end(&mut sample)?;
+
+        let comp = if self.is_compressible(&sample)? {
+            Compression::Zstd
+        } else {
+            Compression::Stored
+        };
+        let reader = Cursor::new(sample).chain(reader);
+
+        match comp {
+            Compression::Stored => Ok((comp, Box::new(reader))),
+            Compression::Zstd => Ok((comp, Box::new(Encoder::new(reader, self.level)?))),
+        }
+    }
+
+    fn is_skipped(&self, path: &Path) -> bool {
+        path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+            self.skip_extensions
+                .iter()
+                .any(|skip| skip.eq_ignore_ascii_case(ext))
+        })
+    }
+
+    fn is_compressible(&self, sample: &[u8]) -> std::io::Result<bool> {
+        if sample.is_empty() {
+            return Ok(true);
+        }
+
+        let trial = zstd::bulk::compress(sample, TRIAL_LEVEL)?;
+        Ok((trial.len() as f64 / sample.len() as f64) <= self.trial_ratio)
+    }
+}
+
+pub fn decompress<'a, R: Read + 'a>(
+    comp: Compression,
+    reader: R,
+) -> std::io::Result<Box<dyn Read + 'a>> {
+    match comp {
+        Compression::Stored => Ok(Box::new(reader)),
+        Compression::Zstd => Ok(Box::new(Decoder::new(reader)?)),
+    }
+}
+
+#[cfg(test)]
+mod test_policy {
+    use super::*;
+    use crate::rcore::key;
+
+    fn roundtrip(policy: &Policy, path: &str, data: &[u8]) -> Compression {
+        let (comp, mut reader) = policy
+            .compress(Path::new(path), data.len() as u64, data)
+            .unwrap();
+
+        let mut stored = vec![];
+        reader.read_to_end(&mut stored).unwrap();
+
+        let mut out = vec![];
+        decompress(comp, &stored[..])
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+
+        assert_eq!(data, &out[..]);
+        comp
+    }
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let key = key::MemKey::new();
+        let mut ret = vec![];
+        while ret.len() < len {
+            ret.extend_from_slice(key.gen_id().as_bytes());
+        }
+        ret
+    }
+
+    #[test]
+    fn default_valid() {
+        Policy::default().validate().unwrap();
+    }
+
+    #[test]
+    fn invalid() {
+        let level = Policy {
+            level: i32::MAX,
+            ..Policy::default()
+        };
+        assert!(level.validate().is_err());
+
+        for trial_ratio in [-0.1, 1.5, f64::NAN] {
+            let ratio = Policy {
+                trial_ratio,
+                ..Policy::default()
+            };
+            assert!(ratio.validate().is_err());
+        }
+
+        let size = Policy {
+            trial_size: 0,
+            ..Policy::default()
+        };
+        assert!(size.validate().is_err());
+    }
+
+    #[test]
+    fn tiny_stored() {
+        let policy = Policy::default();
+        assert_eq!(
+            roundtrip(&policy, "a.txt", b"Hello World!"),
+            Compression::Stored
+        );
+    }
+
+    #[test]
+    fn extension_stored() {
+        let policy = Policy::default();
+        let data = vec![0; 4096];
+        assert_eq!(roundtrip(&policy, "a.JPG", &data), Compression::Stored);
+    }
+
+    #[test]
+    fn random_stored() {
+        let policy = Policy::default();
+        let data = random_data(128 * 1024);
+        assert_eq!(roundtrip(&policy, "a.bin", &data), Compression::Stored);
+    }
+
+    #[test]
+    fn text_compressed() {
+        let policy = Policy::default();
+        let data = b"Hello World!".repeat(20 * 1024);
+        assert_eq!(roundtrip(&policy, "a.txt", &data), Compression::Zstd);
+    }
+}
@@ -62,6 +62,53 @@ pub fn hash<R: Read>(key: &key::MemKey, data: &mut R) -> Result<Hash, std::io::E
     Ok(Hash(hash.finalize()))
 }
 
+// Content hash of a sparse file, its data regions alone can't be told apart from the same data
+// laid out differently so the extents gets folded in. Without extents it is the data hash as is
+pub fn with_extents(key: &key::MemKey, data: Hash, extents: &[(u64, u64)]) -> Hash {
+    if extents.is_empty() {
+        return data;
+    }
+    let mut hash = blake3::Hasher::new_keyed(&key.hmac_key().0);
+    hash.update(data.as_bytes());
+    for (offset, len) in extents {
+        hash.update(&offset.to_le_bytes());
+        hash.update(&len.to_le_bytes());
+    }
+    Hash(hash.finalize())
+}
+
+// Hashes whatever gets read through it, so the content can be hashed while something else
+// consumes it
+pub struct HashReader<R> {
+    inner: R,
+    hash: blake3::Hasher,
+    read: u64,
+}
+
+impl<R: Read> HashReader<R> {
+    pub fn new(key: &key::MemKey, inner: R) -> Self {
+        Self {
+            inner,
+            hash: blake3::Hasher::new_keyed(&key.hmac_key().0),
+            read: 0,
+        }
+    }
+
+    // Hash + count of the bytes read so far
+    pub fn finalize(&self) -> (Hash, u64) {
+        (Hash(self.hash.finalize()), self.read)
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hash.update(&buf[..len]);
+        self.read += len as u64;
+        Ok(len)
+    }
+}
+
 // To encapsulate the hash engine used
 pub fn from_hex(hash: &str) -> Result<Hash, blake3::HexError> {
     blake3::Hash::from_hex(hash).map(Hash)
@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::ffi::OsStringExt as _;
+use std::os::unix::fs::FileTypeExt as _;
+use std::os::unix::fs::MetadataExt as _;
+use std::path::Path;
+
+use log::warn;
+use nix::errno::Errno;
+use nix::unistd::{Whence, lseek};
+
+// Xattrs that matters for a faithful restore but may not show up in the listing
+const PROBE_XATTRS: [&str; 3] = [
+    "security.capability",
+    "system.posix_acl_access",
+    "system.posix_acl_default",
+];
+
+// Name + value of an extended attribute, the name is raw bytes since it need not be utf8
+pub type Xattr = (Vec<u8>, Vec<u8>);
+
+// Offset + length of a region of a sparse file that holds data, everything else is a hole
+pub type Extent = (u64, u64);
+
+// Type of the entry, this gets recorded per entry in the index
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+impl FileType {
+    pub fn to_flag(self) -> u8 {
+        match self {
+            Self::File => 0,
+            Self::Dir => 1,
+            Self::Symlink => 2,
+            Self::Fifo => 3,
+            Self::Socket => 4,
+            Self::BlockDevice => 5,
+            Self::CharDevice => 6,
+        }
+    }
+
+    pub fn from_flag(flag: u8) -> Result<Self, String> {
+        match flag {
+            0 => Ok(Self::File),
+            1 => Ok(Self::Dir),
+            2 => Ok(Self::Symlink),
+            3 => Ok(Self::Fifo),
+            4 => Ok(Self::Socket),
+            5 => Ok(Self::BlockDevice),
+            6 => Ok(Self::CharDevice),
+            x => Err(format!("Unknown file type flag: {x}")),
+        }
+    }
+
+    fn from_fs(ft: fs::FileType) -> Self {
+        if ft.is_dir() {
+            Self::Dir
+        } else if ft.is_symlink() {
+            Self::Symlink
+        } else if ft.is_fifo() {
+            Self::Fifo
+        } else if ft.is_socket() {
+            Self::Socket
+        } else if ft.is_block_device() {
+            Self::BlockDevice
+        } else if ft.is_char_device() {
+            Self::CharDevice
+        } else {
+            Self::File
+        }
+    }
+}
+
+// Everything about an entry that is needed for a faithful restore and for change detection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+
+    // Permission bits only (including suid/sgid/sticky), the type bits are in `file_type`
+    pub mode: u32,
+    pub size: u64,
+
+    // Seconds + nanoseconds since the epoch
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub atime: i64,
+    pub atime_nsec: i64,
+    pub ctime: i64,
+    pub ctime_nsec: i64,
+
+    // Names are kept alongside the ids so that a restore on another box can map them back
+    pub uid: u32,
+    pub gid: u32,
+    pub user: Option<String>,
+    pub group: Option<String>,
+
+    pub inode: u64,
+    pub device: u64,
+
+    // Device number of block/char devices
+    pub rdev: u64,
+}
+
+impl Metadata {
+    pub fn from_fs(meta: &fs::Metadata, names: &mut Names) -> Self {
+        Self {
+            file_type: FileType::from_fs(meta.file_type()),
+            mode: meta.mode() & 0o7777,
+            size: meta.size(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            atime: meta.atime(),
+            atime_nsec: meta.atime_nsec(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            user: names.user(meta.uid()),
+            group: names.group(meta.gid()),
+            inode: meta.ino(),
+            device: meta.dev(),
+            rdev: meta.rdev(),
+        }
+    }
+}
+
+// The subset of the metadata that changes whenever the content of a file does, if it is the
+// same as the parent snapshot's the content is assumed to be unchanged
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub ctime: i64,
+    pub ctime_nsec: i64,
+    pub inode: u64,
+}
+
+impl Fingerprint {
+    pub fn from_fs(meta: &fs::Metadata) -> Self {
+        Self {
+            size: meta.size(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+            inode: meta.ino(),
+        }
+    }
+}
+
+// The uid/gid -> name lookups goes through nss which can be slow (ldap, etc) and the same
+// handful of ids shows up over and over so cache them
+#[derive(Default)]
+pub struct Names {
+    users: HashMap<u32, Option<String>>,
+    groups: HashMap<u32, Option<String>>,
+}
+
+impl Names {
+    pub fn user(&mut self, uid: u32) -> Option<String> {
+        self.users
+            .entry(uid)
+            .or_insert_with(|| {
+                uzers::get_user_by_uid(uid).map(|u| u.name().to_string_lossy().into_owned())
+            })
+            .clone()
+    }
+
+    pub fn group(&mut self, gid: u32) -> Option<String> {
+        self.groups
+            .entry(gid)
+            .or_insert_with(|| {
+                uzers::get_group_by_gid(gid).map(|g| g.name().to_string_lossy().into_owned())
+            })
+            .clone()
+    }
+}
+
+// The other way around, maps the recorded names to the ids on the box being restored to
+#[derive(Default)]
+pub struct Ids {
+    users: HashMap<String, Option<u32>>,
+    groups: HashMap<String, Option<u32>>,
+}
+
+impl Ids {
+    pub fn uid(&mut self, user: &str) -> Option<u32> {
+        *self
+            .users
+            .entry(user.to_owned())
+            .or_insert_with(|| uzers::get_user_by_name(user).map(|u| u.uid()))
+    }
+
+    pub fn gid(&mut self, group: &str) -> Option<u32> {
+        *self
+            .groups
+            .entry(group.to_owned())
+            .or_insert_with(|| uzers::get_group_by_name(group).map(|g| g.gid()))
+    }
+}
+
+// Read the xattrs (including acls) of the entry itself, symlinks are not followed
+pub fn read_xattrs(path: &Path) -> io::Result<Vec<Xattr>> {
+    let mut names: Vec<OsString> = match xattr::list(path) {
+        Ok(list) => list.collect(),
+        // Filesystem without xattr support
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    for probe in PROBE_XATTRS {
+        if !names.iter().any(|n| n == probe) {
+            names.push(probe.into());
+        }
+    }
+
+    let mut ret = vec![];
+    for name in names {
+        match xattr::get(path, &name) {
+            Ok(Some(value)) => ret.push((name.into_vec(), value)),
+            Ok(None) => (),
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => (),
+            Err(e) => return Err(e),
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+// Setting security.* and trusted.* needs privileges and acls needs ownership, so anything that
+// gets refused is logged and skipped instead of failing the restore
+pub fn write_xattrs(path: &Path, xattrs: &[Xattr]) -> io::Result<()> {
+    for (name, value) in xattrs {
+        let name = OsStr::from_bytes(name);
+        match xattr::set(path, name, value) {
+            Ok(()) => (),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+                ) =>
+            {
+                warn!("XATTR: {} - {name:?}: {e}", path.display());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// The data regions of the file up to size, None when it has no holes. Filesystems that can't
+// tell where the holes are gets treated as dense
+pub fn read_extents(file: &fs::File, size: u64) -> io::Result<Option<Vec<Extent>>> {
+    let end = i64::try_from(size).map_err(io::Error::other)?;
+    let mut ret = vec![];
+    let mut pos = 0;
+    while pos < end {
+        let data = match lseek(file, pos, Whence::SeekData) {
+            Ok(data) if data < end => data,
+            // Only holes left
+            Ok(_) | Err(Errno::ENXIO) => break,
+            Err(Errno::EINVAL) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let hole = lseek(file, data, Whence::SeekHole)?.min(end);
+        ret.push((
+            u64::try_from(data).map_err(io::Error::other)?,
+            u64::try_from(hole - data).map_err(io::Error::other)?,
+        ));
+        pos = hole;
+    }
+
+    if size == 0 || ret == [(0, size)] {
+        return Ok(None);
+    }
+    Ok(Some(ret))
+}
+
+// Reads the data regions back to back, skipping over the holes
+pub struct ExtentReader<'a, R> {
+    inner: R,
+    extents: &'a [Extent],
+    left: u64,
+}
+
+impl<'a, R: Read + Seek> ExtentReader<'a, R> {
+    pub const fn new(inner: R, extents: &'a [Extent]) -> Self {
+        Self {
+            inner,
+            extents,
+            left: 0,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for ExtentReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.left == 0 {
+                let Some((&(offset, len), rest)) = self.extents.split_first() else {
+                    return Ok(0);
+                };
+                self.inner.seek(SeekFrom::Start(offset))?;
+                self.left = len;
+                self.extents = rest;
+                continue;
+            }
+
+            let max = usize::try_from(self.left)
+                .unwrap_or(usize::MAX)
+                .min(buf.len());
+            let n = self.inner.read(&mut buf[..max])?;
+            if n == 0 {
+                // Got truncated, the rest of the extent is gone
+                self.left = 0;
+                continue;
+            }
+            self.left -= n as u64;
+            return Ok(n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_meta {
+    use super::*;
+
+    #[test]
+    fn file_type_flags() {
+        for ft in [
+            FileType::File,
+            FileType::Dir,
+            FileType::Symlink,
+            FileType::Fifo,
+            FileType::Socket,
+            FileType::BlockDevice,
+            FileType::CharDevice,
+        ] {
+            assert_eq!(FileType::from_flag(ft.to_flag()).unwrap(), ft);
+        }
+        assert!(FileType::from_flag(7).is_err());
+    }
+
+    #[test]
+    fn from_fs() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        io::Write::write_all(&mut file.as_file(), b"Hello World!").unwrap();
+
+        let meta = Metadata::from_fs(&file.as_file().metadata().unwrap(), &mut Names::default());
+        assert_eq!(meta.file_type, FileType::File);
+        assert_eq!(meta.size, 12);
+        assert_eq!(meta.mode, 0o600);
+    }
+
+    #[test]
+    fn xattrs_roundtrip() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let xattrs = vec![(b"user.rozen".to_vec(), b"Hello World!".to_vec())];
+
+        // Not every filesystem the tests run on has user xattrs
+        if xattr::set(file.path(), "user.probe", b"").is_err() {
+            return;
+        }
+        xattr::remove(file.path(), "user.probe").unwrap();
+
+        write_xattrs(file.path(), &xattrs).unwrap();
+        assert_eq!(read_xattrs(file.path()).unwrap(), xattrs);
+    }
+
+    #[test]
+    fn extents() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        let file = temp.as_file_mut();
+        file.set_len(4 << 20).unwrap();
+        file.seek(SeekFrom::Start(1 << 20)).unwrap();
+        io::Write::write_all(file, b"Hello World!").unwrap();
+
+        // Whatever the filesystem makes of the holes the extents covers all of the data
+        let extents = read_extents(file, 4 << 20)
+            .unwrap()
+            .unwrap_or_else(|| vec![(0, 4 << 20)]);
+        let mut data = vec![];
+        ExtentReader::new(&mut *file, &extents)
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data.iter().filter(|b| **b != 0).count(), 12);
+        assert!(extents.iter().all(|(off, len)| off + len <= 4 << 20));
+
+        let mut all = fs::read(temp.path()).unwrap();
+        for (off, len) in extents {
+            let off = usize::try_from(off).unwrap();
+            all[off..off + usize::try_from(len).unwrap()].fill(0);
+        }
+        assert!(all.iter().all(|b| *b == 0));
+    }
+}
@@ -1,4 +1,6 @@
 pub mod buf;
+pub mod compress;
 pub mod crypto;
 pub mod hash;
 pub mod key;
+pub mod meta;
@@ -7,6 +7,7 @@ pub mod s3;
 use std::error::Error;
 use std::fmt;
 use std::io::{Read, Write};
+use std::time::SystemTime;
 
 use crate::rcore::hash;
 
@@ -17,6 +18,9 @@ pub enum Typ {
     Index,
     Pack,
 
+    // Map of the packs an unfinished append has uploaded so far
+    Checkpoint,
+
     // Test only, for storing testing related stuff
     // TODO: REMOVE
     TEST,
@@ -28,12 +32,21 @@ impl fmt::Display for Typ {
             Self::Map => write!(f, "map"),
             Self::Index => write!(f, "index"),
             Self::Pack => write!(f, "pack"),
+            Self::Checkpoint => write!(f, "checkpoint"),
             Self::TEST => write!(f, "TEST"),
         }
     }
 }
 
-pub trait Remote {
+// Stored size + last modified time of a file in the remote
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+// Remotes are shared across the threads of the backup pipeline
+pub trait Remote: Send + Sync {
     fn list_keys(&self, typ: Typ) -> Result<Box<dyn Iterator<Item = String>>, Box<dyn Error>>;
 
     // Api for reading/writing filenames
@@ -43,21 +56,47 @@ pub trait Remote {
         filename: &str,
         reader: R,
     ) -> Result<(), Box<dyn Error>>;
-    fn read_filename(&mut self, typ: Typ, filename: &str) -> Result<Box<dyn Read>, Box<dyn Error>>;
+    fn read_filename(
+        &mut self,
+        typ: Typ,
+        filename: &str,
+    ) -> Result<Box<dyn Read + Send>, Box<dyn Error>>;
 
     // Api for reading/Writing hashes to the remote
     fn write<R: Read>(&self, typ: Typ, key: hash::Hash, reader: R) -> Result<(), Box<dyn Error>> {
         self.write_filename(typ, &hash::to_hex(key), reader)
     }
-    fn read(&mut self, typ: Typ, key: hash::Hash) -> Result<Box<dyn Read>, Box<dyn Error>> {
+    fn read(&mut self, typ: Typ, key: hash::Hash) -> Result<Box<dyn Read + Send>, Box<dyn Error>> {
         self.read_filename(typ, &hash::to_hex(key))
     }
 
+    // Api for deleting, deleting a key that does not exist is not an error
+    fn delete_filename(&self, typ: Typ, filename: &str) -> Result<(), Box<dyn Error>>;
+
+    fn delete(&self, typ: Typ, key: hash::Hash) -> Result<(), Box<dyn Error>> {
+        self.delete_filename(typ, &hash::to_hex(key))
+    }
+
+    // Api for stat, None if the key does not exist
+    fn stat_filename(&self, typ: Typ, filename: &str) -> Result<Option<Stat>, Box<dyn Error>>;
+
+    fn stat(&self, typ: Typ, key: hash::Hash) -> Result<Option<Stat>, Box<dyn Error>> {
+        self.stat_filename(typ, &hash::to_hex(key))
+    }
+
     // Write Multipart, give a write handle and it will handle the streaming
     // TODO: consider if finalize on a trait is better than 'flush' for our purposes
-    fn write_multi_filename(&self, typ: Typ, key: &str) -> Result<Box<dyn Write>, Box<dyn Error>>;
+    fn write_multi_filename(
+        &self,
+        typ: Typ,
+        key: &str,
+    ) -> Result<Box<dyn Write + Send>, Box<dyn Error>>;
 
-    fn write_multi(&self, typ: Typ, key: hash::Hash) -> Result<Box<dyn Write>, Box<dyn Error>> {
+    fn write_multi(
+        &self,
+        typ: Typ,
+        key: hash::Hash,
+    ) -> Result<Box<dyn Write + Send>, Box<dyn Error>> {
         self.write_multi_filename(typ, &hash::to_hex(key))
     }
 }
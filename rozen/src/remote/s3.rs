@@ -1,24 +1,27 @@
 use aws_sdk_s3::Client;
+use aws_sdk_s3::operation::head_object::HeadObjectError;
 use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::types::CompletedMultipartUpload;
 use aws_sdk_s3::types::CompletedPart;
-use bytes::Buf;
+use bytes::Buf as _;
 use std::error::Error;
+use std::io;
 use std::io::{Read, Write, copy};
 use std::mem;
+use std::time::SystemTime;
 use tokio::runtime::Runtime;
 
-// Single threaded but we are on one thread here for now
-use std::rc::Rc;
+use std::sync::Arc;
 
 use crate::rcore::buf::flush_buf;
 
 use crate::remote::Remote;
+use crate::remote::Stat;
 use crate::remote::Typ;
 
 pub struct S3 {
-    client: Rc<Client>,
-    rt: Rc<Runtime>,
+    client: Arc<Client>,
+    rt: Arc<Runtime>,
     bucket: String,
 }
 
@@ -31,27 +34,42 @@ impl S3 {
 
         let client = rt.block_on(connect(endpoint));
 
-        Ok(S3 {
-            client: Rc::new(client),
-            rt: Rc::new(rt),
-            bucket: bucket.to_string(),
+        Ok(Self {
+            client: Arc::new(client),
+            rt: Arc::new(rt),
+            bucket: bucket.to_owned(),
         })
     }
 }
 
 impl Remote for S3 {
-    fn list_keys(&self, typ: Typ) -> Result<Box<dyn Iterator<Item = String>>, String> {
-        let call = self
-            .client
-            .list_objects_v2()
-            .bucket(&self.bucket)
-            .prefix(typ.to_string())
-            .send();
+    fn list_keys(&self, typ: Typ) -> Result<Box<dyn Iterator<Item = String>>, Box<dyn Error>> {
+        // Listings are capped at 1000 keys, so page through them
+        let prefix = format!("{typ}/");
+        let mut keys = vec![];
+        let mut token = None;
+        loop {
+            let call = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(&prefix)
+                .set_continuation_token(token)
+                .send();
 
-        let res = self.rt.block_on(async { call.await })?;
-        let contents = res.contents?;
+            let res = self.rt.block_on(call)?;
+            keys.extend(
+                res.contents()
+                    .iter()
+                    .filter_map(|x| x.key()?.strip_prefix(&prefix).map(str::to_owned)),
+            );
 
-        Ok(Box::new(contents.into_iter().map(|x| x.key?)))
+            token = res.next_continuation_token;
+            if token.is_none() {
+                break;
+            }
+        }
+        Ok(Box::new(keys.into_iter()))
     }
 
     // TODO: this and the multipart api needs to also do various checksums to pass on to s3
@@ -61,7 +79,7 @@ impl Remote for S3 {
         typ: Typ,
         filename: &str,
         mut reader: R,
-    ) -> Result<(), String> {
+    ) -> Result<(), Box<dyn Error>> {
         // TODO: Less bad, still buffer it all in memory, but we can at least
         // manage the read here so we should be able to do something reasonable
         // here at some point
@@ -75,56 +93,97 @@ impl Remote for S3 {
             .put_object()
             .body(stream)
             .bucket(&self.bucket)
-            .key(format!("{}/{}", typ, filename))
+            .key(format!("{typ}/{filename}"))
             .send();
 
-        let _res = self.rt.block_on(async { call.await })?;
-
+        self.rt.block_on(call)?;
         Ok(())
     }
 
     // TODO: if this is a multipart uploaded it could be possible to fetch each part and
     // verify its checksum and so on before returning it to the backup system?
-    fn read_filename(&mut self, typ: Typ, filename: &str) -> Result<Box<dyn Read>, String> {
+    fn read_filename(
+        &mut self,
+        typ: Typ,
+        filename: &str,
+    ) -> Result<Box<dyn Read + Send>, Box<dyn Error>> {
         // Do s3 dance to fetch a object and buffer it locally
         let call = self
             .client
             .get_object()
             .bucket(&self.bucket)
-            .key(format!("{}/{}", typ, filename))
+            .key(format!("{typ}/{filename}"))
             .send();
 
-        let res = self.rt.block_on(async { call.await })?;
-
-        let body_call = res.body.collect();
-        let data = self.rt.block_on(async { body_call.await? });
+        let res = self.rt.block_on(call)?;
+        let data = self.rt.block_on(res.body.collect())?;
         let mut data_read = data.reader();
 
         let mut buf = Vec::new();
         copy(&mut data_read, &mut buf)?;
 
         Ok(Box::new(S3Read {
-            _client: self.client.clone(),
-            _rt: self.rt.clone(),
+            _client: Arc::clone(&self.client),
+            _rt: Arc::clone(&self.rt),
             buf,
         }))
     }
 
-    fn write_multi_filename(&self, typ: Typ, key: &str) -> Result<Box<dyn Write>, String> {
+    // S3 deletes of missing keys already succeed
+    fn delete_filename(&self, typ: Typ, filename: &str) -> Result<(), Box<dyn Error>> {
+        let call = self
+            .client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(format!("{typ}/{filename}"))
+            .send();
+
+        self.rt.block_on(call)?;
+        Ok(())
+    }
+
+    fn stat_filename(&self, typ: Typ, filename: &str) -> Result<Option<Stat>, Box<dyn Error>> {
+        let call = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(format!("{typ}/{filename}"))
+            .send();
+
+        match self.rt.block_on(call) {
+            Ok(res) => Ok(Some(Stat {
+                size: u64::try_from(res.content_length.unwrap_or(0))?,
+                modified: SystemTime::try_from(res.last_modified.ok_or("last_modified")?)?,
+            })),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(HeadObjectError::is_not_found) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_multi_filename(
+        &self,
+        typ: Typ,
+        key: &str,
+    ) -> Result<Box<dyn Write + Send>, Box<dyn Error>> {
         let call = self
             .client
             .create_multipart_upload()
             .bucket(&self.bucket)
-            .key(format!("{}/{}", typ, key))
+            .key(format!("{typ}/{key}"))
             .send();
 
-        let res = self.rt.block_on(async { call.await })?;
+        let res = self.rt.block_on(call)?;
 
         Ok(Box::new(S3Multi {
-            client: self.client.clone(),
-            rt: self.rt.clone(),
-            key: key.to_string(),
-            id: res.upload_id?,
+            client: Arc::clone(&self.client),
+            rt: Arc::clone(&self.rt),
+            key: key.to_owned(),
+            id: res.upload_id.ok_or("upload_id")?,
             bucket: self.bucket.clone(),
             part: Vec::new(),
             part_id: 1,
@@ -135,8 +194,8 @@ impl Remote for S3 {
 }
 
 struct S3Multi {
-    client: Rc<Client>,
-    rt: Rc<Runtime>,
+    client: Arc<Client>,
+    rt: Arc<Runtime>,
     key: String,
     id: String,
     bucket: String,
@@ -151,18 +210,18 @@ struct S3Multi {
 const BUFFER_TARGET: usize = 6 * 1024 * 1024;
 
 impl Write for S3Multi {
-    fn write(&mut self, in_buf: &[u8]) -> Result<usize, std::io::Error> {
+    fn write(&mut self, in_buf: &[u8]) -> io::Result<usize> {
         // append to t_buf
         self.t_buf.extend(in_buf);
-        self.upload_part(false);
+        self.upload_part(false)?;
 
         Ok(in_buf.len())
     }
 
     // TODO: not sure if this is proper use of flush or if we should have a finalize call instead
-    fn flush(&mut self) -> Result<(), std::io::Error> {
+    fn flush(&mut self) -> io::Result<()> {
         // Finalaize the stream
-        self.upload_part(true);
+        self.upload_part(true)?;
 
         let call = self
             .client
@@ -177,13 +236,13 @@ impl Write for S3Multi {
             )
             .send();
 
-        let _res = self.rt.block_on(async { call.await })?;
+        self.rt.block_on(call).map_err(io::Error::other)?;
         Ok(())
     }
 }
 
 impl S3Multi {
-    fn upload_part(&mut self, last: bool) {
+    fn upload_part(&mut self, last: bool) -> io::Result<()> {
         // If last part to upload *or* at least 6mb accumulated upload
         if (last && !self.t_buf.is_empty()) || self.t_buf.len() >= BUFFER_TARGET {
             // Swap the self.t_buf with a empty one and own it
@@ -201,12 +260,12 @@ impl S3Multi {
                 .part_number(self.part_id)
                 .send();
 
-            let res = self.rt.block_on(async { call.await })?;
+            let res = self.rt.block_on(call).map_err(io::Error::other)?;
 
             // Collect info to make a CompletePart to then record in finalize
             self.part.push(
                 CompletedPart::builder()
-                    .e_tag(res.e_tag?)
+                    .e_tag(res.e_tag.ok_or_else(|| io::Error::other("e_tag"))?)
                     .part_number(self.part_id)
                     .build(),
             );
@@ -214,24 +273,25 @@ impl S3Multi {
             // Increment the part number, clear the buffer
             self.part_id += 1;
         }
+        Ok(())
     }
 }
 
 // TODO: properly implement streaming
 struct S3Read {
-    _client: Rc<Client>,
-    _rt: Rc<Runtime>,
+    _client: Arc<Client>,
+    _rt: Arc<Runtime>,
     buf: Vec<u8>,
 }
 
 impl Read for S3Read {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         Ok(flush_buf(&mut self.buf, buf))
     }
 }
 
 async fn connect(endpoint: &'static str) -> Client {
-    let conf = aws_config::load_from_env().await;
+    let conf = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
     let s3_conf = aws_sdk_s3::config::Builder::from(&conf)
         .endpoint_url(endpoint)
         .build();
@@ -4,20 +4,20 @@ use rusqlite::Connection;
 use std::error::Error;
 use std::io::ErrorKind;
 use std::io::{Cursor, Read, Write};
-
-// Single threaded but we are on one thread here for now
-use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::rcore::buf::fill_buf;
 
 use crate::remote::Remote;
+use crate::remote::Stat;
 use crate::remote::Typ;
 
 #[expect(clippy::identity_op)]
 const CHUNK_SIZE: usize = 1 * 1024;
 
 pub struct SqlVFS {
-    conn: Rc<Connection>,
+    conn: Arc<Mutex<Connection>>,
 }
 
 impl SqlVFS {
@@ -37,18 +37,30 @@ impl SqlVFS {
                 content BLOB NOT NULL,
                 UNIQUE(key, typ, chunk)
              );
+             CREATE TABLE IF NOT EXISTS meta (
+                key VARCHAR NOT NULL,
+                typ VARCHAR NOT NULL,
+                modified INTEGER NOT NULL,
+                UNIQUE(key, typ)
+             );
              COMMIT;",
         )?;
 
         Ok(Self {
-            conn: Rc::new(conn),
+            conn: Arc::new(Mutex::new(conn)),
         })
     }
 }
 
+fn lock(conn: &Mutex<Connection>) -> Result<MutexGuard<'_, Connection>, Box<dyn Error>> {
+    conn.lock()
+        .map_err(|_| "SqlVFS connection lock poisoned".into())
+}
+
 impl Remote for SqlVFS {
     fn list_keys(&self, typ: Typ) -> Result<Box<dyn Iterator<Item = String>>, Box<dyn Error>> {
-        let mut stmt = self.conn.prepare_cached(
+        let conn = lock(&self.conn)?;
+        let mut stmt = conn.prepare_cached(
             "SELECT DISTINCT key
                  FROM blob
                  WHERE typ = ?",
@@ -73,8 +85,13 @@ impl Remote for SqlVFS {
         write_filename(&self.conn, typ, filename, reader)
     }
 
-    fn read_filename(&mut self, typ: Typ, filename: &str) -> Result<Box<dyn Read>, Box<dyn Error>> {
-        let mut stmt = self.conn.prepare_cached(
+    fn read_filename(
+        &mut self,
+        typ: Typ,
+        filename: &str,
+    ) -> Result<Box<dyn Read + Send>, Box<dyn Error>> {
+        let conn = lock(&self.conn)?;
+        let mut stmt = conn.prepare_cached(
             "SELECT content
                  FROM blob
                  WHERE key = ?
@@ -94,9 +111,43 @@ impl Remote for SqlVFS {
         )))
     }
 
-    fn write_multi_filename(&self, typ: Typ, key: &str) -> Result<Box<dyn Write>, Box<dyn Error>> {
+    fn delete_filename(&self, typ: Typ, filename: &str) -> Result<(), Box<dyn Error>> {
+        let conn = lock(&self.conn)?;
+        delete_filename(&conn, typ, filename)
+    }
+
+    fn stat_filename(&self, typ: Typ, filename: &str) -> Result<Option<Stat>, Box<dyn Error>> {
+        let conn = lock(&self.conn)?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT COUNT(*), SUM(LENGTH(b.content)), MAX(m.modified)
+                 FROM blob b
+                 LEFT JOIN meta m ON m.key = b.key AND m.typ = b.typ
+                 WHERE b.key = ?
+                 AND b.typ = ?",
+        )?;
+
+        let (count, size, modified): (i64, Option<i64>, Option<i64>) = stmt
+            .query_row(rs::params![filename, typ.to_string()], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?;
+
+        // Files written before the meta table existed are treated as old as can be
+        if count == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Stat {
+            size: u64::try_from(size.unwrap_or(0))?,
+            modified: UNIX_EPOCH + Duration::from_secs(u64::try_from(modified.unwrap_or(0))?),
+        }))
+    }
+
+    fn write_multi_filename(
+        &self,
+        typ: Typ,
+        key: &str,
+    ) -> Result<Box<dyn Write + Send>, Box<dyn Error>> {
         Ok(Box::new(VFSWrite {
-            conn: Rc::clone(&self.conn),
+            conn: Arc::clone(&self.conn),
             key: key.to_owned(),
             t_buf: Vec::new(),
             typ,
@@ -105,7 +156,7 @@ impl Remote for SqlVFS {
 }
 
 struct VFSWrite {
-    conn: Rc<Connection>,
+    conn: Arc<Mutex<Connection>>,
     key: String,
     t_buf: Vec<u8>,
     typ: Typ,
@@ -127,18 +178,15 @@ impl Write for VFSWrite {
 }
 
 fn write_filename<R: Read>(
-    conn: &Rc<Connection>,
+    conn: &Mutex<Connection>,
     typ: Typ,
     filename: &str,
     mut reader: R,
 ) -> Result<(), Box<dyn Error>> {
+    let conn = lock(conn)?;
+
     // Delete any key chunks that exists before
-    conn.prepare_cached(
-        "DELETE FROM blob
-         WHERE key = ?
-         AND typ = ?",
-    )?
-    .execute(rs::params![filename, typ.to_string()])?;
+    delete_filename(&conn, typ, filename)?;
 
     // Insert new data
     let mut chunk_idx: i64 = 0;
@@ -167,6 +215,32 @@ fn write_filename<R: Read>(
             }
         }
     }
+
+    let modified = i64::try_from(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())?;
+    conn.prepare_cached(
+        "INSERT INTO meta
+             (key, typ, modified)
+             VALUES
+             (?, ?, ?)",
+    )?
+    .execute(rs::params![filename, typ.to_string(), modified])?;
+    Ok(())
+}
+
+fn delete_filename(conn: &Connection, typ: Typ, filename: &str) -> Result<(), Box<dyn Error>> {
+    conn.prepare_cached(
+        "DELETE FROM blob
+         WHERE key = ?
+         AND typ = ?",
+    )?
+    .execute(rs::params![filename, typ.to_string()])?;
+
+    conn.prepare_cached(
+        "DELETE FROM meta
+         WHERE key = ?
+         AND typ = ?",
+    )?
+    .execute(rs::params![filename, typ.to_string()])?;
     Ok(())
 }
 
@@ -216,4 +290,31 @@ mod tests {
 
         assert_eq!(val, "Data Test");
     }
+
+    #[test]
+    fn delete_read_write() {
+        let back = SqlVFS::new(None).unwrap();
+        let key = "test-key";
+
+        let data: &[u8; 9] = b"Test Data";
+        let b = Cursor::new(data);
+        back.write_filename(Typ::Pack, key, b).unwrap();
+        back.delete_filename(Typ::Pack, key).unwrap();
+
+        assert_eq!(back.list_keys(Typ::Pack).unwrap().count(), 0);
+        assert!(back.stat_filename(Typ::Pack, key).unwrap().is_none());
+    }
+
+    #[test]
+    fn stat_write() {
+        let back = SqlVFS::new(None).unwrap();
+        let key = "test-key";
+
+        let data = vec![1; 2500];
+        back.write_filename(Typ::Pack, key, &data[..]).unwrap();
+
+        let stat = back.stat_filename(Typ::Pack, key).unwrap().unwrap();
+        assert_eq!(stat.size, 2500);
+        assert!(stat.modified.elapsed().unwrap().as_secs() < 60);
+    }
 }
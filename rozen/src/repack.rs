@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::Write;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use log::{debug, info};
+
+use crate::rcore::hash;
+use crate::rcore::key;
+
+use crate::gc::checkpoint_packs;
+
+use crate::rarc::pack::PackBuilder;
+use crate::rarc::pack::PackOut;
+
+use crate::remote::Remote;
+use crate::remote::Typ;
+
+use crate::snapshot::Keys;
+use crate::snapshot::Options;
+
+use crate::sql::Index;
+use crate::sql::Map;
+
+// Pack being filled plus the content already in it
+type Current = (PackBuilder<Box<dyn Write + Send>>, HashSet<hash::Hash>);
+
+#[derive(Debug, Default)]
+pub struct Report {
+    // Old packs that got rewritten then deleted
+    pub packs_removed: usize,
+    pub packs_written: usize,
+
+    // Bytes of the records carried over into the new packs vs left behind
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+
+    // Packs left alone because they are younger than the grace period or a checkpoint of an
+    // unfinished append points to them
+    pub recent: usize,
+    pub checkpointed: usize,
+}
+
+// Compaction
+//  1. Load every snapshot, a record (content in a pack) is live if a map points content to that
+//     pack and the index of the same snapshot references the content
+//  2. Find the packs where the live bytes / total bytes of its records is below the threshold,
+//     the maps have the length of every record + pack so no pack needs reading for this
+//  3. Copy the live FHDR+EDAT records as they are into new packs, the EDAT stays encrypted
+//  4. Repoint the maps at the new packs and write them back
+//  5. Only once all maps are written, delete the old packs
+//
+// Packs that no map references are left alone, that is the garbage collector's job. Same as the
+// garbage collector, packs that a checkpoint points to or that are younger than the grace period
+// are left alone too since an unfinished append or one in progress may still point to them
+pub fn repack<B: Remote>(
+    key: &key::MemKey,
+    remote: &mut B,
+    snapshots: &[Keys],
+    options: &Options,
+    threshold: f64,
+    grace: Duration,
+) -> Result<Report, Box<dyn Error>> {
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(format!("Repack threshold ({threshold}) is outside of 0..=1").into());
+    }
+    let mut report = Report::default();
+
+    // 1. (pack, content) records that are still referenced, with the live + total bytes of each
+    // pack they are in
+    let mut live: HashSet<(hash::Hash, hash::Hash)> = HashSet::new();
+    let mut packs: HashMap<hash::Hash, (u64, u64)> = HashMap::new();
+    let mut maps = Vec::new();
+    for snapshot in snapshots {
+        let index = Index::load(&mut remote.read_filename(Typ::Index, &snapshot.index)?, key)?;
+        let map = Map::load(&mut remote.read_filename(Typ::Map, &snapshot.map)?, key)?;
+
+        let contents = index.content_hashes()?;
+        for (content, _, pack, length) in map.chunks()? {
+            if contents.contains(&content) && live.insert((pack, content)) {
+                let total = map.find_pack(pack)?.ok_or("pack_length")?;
+                let (live_bytes, total_bytes) = packs.entry(pack).or_default();
+                *live_bytes += length;
+                *total_bytes = total;
+            }
+        }
+        maps.push((&snapshot.map, map));
+    }
+
+    // 2. Packs below the live threshold
+    let rewrite = below_threshold(key, remote, packs, threshold, grace, &mut report)?;
+
+    // 3. Copy the live records over, a pack can't hold the same content twice so if the current
+    // pack already has it (ie another part of the same content) start a new pack
+    let mut moved: HashMap<(hash::Hash, hash::Hash), hash::Hash> = HashMap::new();
+    let mut written: HashMap<hash::Hash, u64> = HashMap::new();
+    let mut current: Option<Current> = None;
+
+    for old in &rewrite {
+        let pack_file = PackOut::load(&mut remote.read(Typ::Pack, *old)?, key)?;
+
+        for (content, length) in pack_file.records() {
+            if !live.contains(&(*old, content)) {
+                report.dead_bytes += u64::try_from(length)?;
+                continue;
+            }
+
+            if current
+                .as_ref()
+                .is_some_and(|(_, has)| has.contains(&content))
+            {
+                let (pack, _) = current.take().ok_or("pack_take")?;
+                finalize(pack, key, &mut written)?;
+            }
+
+            let (pack, has) = if let Some(v) = current.as_mut() {
+                v
+            } else {
+                let pack_id = key.gen_id();
+                let multiwrite = remote.write_multi(Typ::Pack, pack_id)?;
+                current.insert((
+                    PackBuilder::new(pack_id, multiwrite, options.pack.target_size)?,
+                    HashSet::new(),
+                ))
+            };
+
+            let data = pack_file.find_hash(content).ok_or("find_hash")?;
+            moved.insert((*old, content), pack.id);
+            has.insert(content);
+            report.live_bytes += u64::try_from(length)?;
+
+            if pack.append(content, &mut &data[..])? {
+                let (pack, _) = current.take().ok_or("pack_take")?;
+                finalize(pack, key, &mut written)?;
+            }
+        }
+    }
+
+    if let Some((pack, _)) = current.take() {
+        finalize(pack, key, &mut written)?;
+    }
+    report.packs_written = written.len();
+
+    // 4. Repoint the maps, anything still pointing at an old pack is dead so drop it
+    for (name, map) in maps {
+        let mut changed = 0;
+        for ((old, content), new) in &moved {
+            let count = map.move_chunk(*content, *old, *new)?;
+            if count > 0 {
+                map.insert_pack(*new, *written.get(new).ok_or("pack_length")?)?;
+            }
+            changed += count;
+        }
+        for old in &rewrite {
+            changed += map.remove_pack(*old)?;
+        }
+
+        if changed > 0 {
+            info!("MAP: {name} - {changed} chunks moved");
+            let writer = remote.write_multi_filename(Typ::Map, name)?;
+            map.unload(key, &options.compression, writer)?;
+        }
+    }
+
+    // 5. Everything now points at the new packs
+    for old in rewrite {
+        info!("DELETE: {old:?}");
+        remote.delete(Typ::Pack, old)?;
+        report.packs_removed += 1;
+    }
+
+    Ok(report)
+}
+
+// Packs where the live bytes / total bytes is below the threshold, other than the ones that an
+// unfinished append or one in progress may still point to
+fn below_threshold<B: Remote>(
+    key: &key::MemKey,
+    remote: &mut B,
+    packs: HashMap<hash::Hash, (u64, u64)>,
+    threshold: f64,
+    grace: Duration,
+    report: &mut Report,
+) -> Result<Vec<hash::Hash>, Box<dyn Error>> {
+    let cutoff = SystemTime::now() - grace;
+    let (resumable, _) = checkpoint_packs(key, remote)?;
+
+    let mut rewrite = Vec::new();
+    for (pack, (live_bytes, total_bytes)) in packs {
+        debug!("PACK: {pack:?} - live: {live_bytes} total: {total_bytes}");
+        if (live_bytes as f64) >= threshold * (total_bytes as f64) {
+            continue;
+        }
+
+        if resumable.contains(&pack) {
+            debug!("CHECKPOINT: {pack:?}");
+            report.checkpointed += 1;
+            continue;
+        }
+
+        // Gone already, ie another repack got to it first
+        let Some(stat) = remote.stat(Typ::Pack, pack)? else {
+            continue;
+        };
+        if stat.modified > cutoff {
+            debug!("RECENT: {pack:?}");
+            report.recent += 1;
+            continue;
+        }
+        rewrite.push(pack);
+    }
+    Ok(rewrite)
+}
+
+// Uploads the rest of the pack, keeping the length of its records for the maps
+fn finalize(
+    pack: PackBuilder<Box<dyn Write + Send>>,
+    key: &key::MemKey,
+    written: &mut HashMap<hash::Hash, u64>,
+) -> Result<(), Box<dyn Error>> {
+    written.insert(pack.id, pack.records_size()?);
+    pack.finalize(key)?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "sql"))]
+mod test_repack {
+    use super::*;
+    use std::io::Read as _;
+    use std::path::Path;
+
+    use crate::rcore::compress;
+    use crate::rcore::meta;
+    use crate::remote::sql::SqlVFS;
+
+    // One pack with 3 records, only the first one is still in the index of the snapshot
+    fn mostly_dead(
+        key: &key::MemKey,
+        remote: &SqlVFS,
+        options: &Options,
+    ) -> (Keys, hash::Hash, Vec<hash::Hash>) {
+        let index = Index::new().unwrap();
+        let map = Map::new().unwrap();
+
+        let contents: Vec<hash::Hash> = (0..3).map(|_| key.gen_id()).collect();
+        let data = vec![7; 1024];
+
+        let old = key.gen_id();
+        let mut pack = PackBuilder::new(
+            old,
+            remote.write_multi(Typ::Pack, old).unwrap(),
+            options.pack.target_size,
+        )
+        .unwrap();
+        for content in &contents {
+            let start = pack.records_size().unwrap();
+            pack.append(*content, &mut &data[..]).unwrap();
+            let length = pack.records_size().unwrap() - start;
+            map.insert_chunk(*content, 0, old, compress::Compression::Stored, length)
+                .unwrap();
+        }
+        map.insert_pack(old, pack.records_size().unwrap()).unwrap();
+        pack.finalize(key).unwrap();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let meta = meta::Metadata::from_fs(
+            &file.as_file().metadata().unwrap(),
+            &mut meta::Names::default(),
+        );
+        index
+            .insert_file(Path::new("a"), &meta, 0, contents[0], None)
+            .unwrap();
+
+        let snapshot = Keys {
+            index: "I-1".to_owned(),
+            map: "M-1".to_owned(),
+        };
+        index
+            .unload(
+                key,
+                &options.compression,
+                &[],
+                remote
+                    .write_multi_filename(Typ::Index, &snapshot.index)
+                    .unwrap(),
+            )
+            .unwrap();
+        map.unload(
+            key,
+            &options.compression,
+            remote
+                .write_multi_filename(Typ::Map, &snapshot.map)
+                .unwrap(),
+        )
+        .unwrap();
+        (snapshot, old, contents)
+    }
+
+    #[test]
+    fn mostly_dead_pack() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let options = Options::default();
+        let (snapshot, old, contents) = mostly_dead(&key, &remote, &options);
+
+        let report = repack(
+            &key,
+            &mut remote,
+            std::slice::from_ref(&snapshot),
+            &options,
+            0.5,
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert_eq!(report.packs_removed, 1);
+        assert_eq!(report.packs_written, 1);
+
+        let mut gone = vec![];
+        remote
+            .read(Typ::Pack, old)
+            .unwrap()
+            .read_to_end(&mut gone)
+            .unwrap();
+        assert!(gone.is_empty());
+
+        let map = Map::load(
+            &mut remote.read_filename(Typ::Map, &snapshot.map).unwrap(),
+            &key,
+        )
+        .unwrap();
+        assert!(map.find_packs(contents[1]).unwrap().is_empty());
+
+        let new = map.find_packs(contents[0]).unwrap();
+        assert_eq!(new.len(), 1);
+
+        // The new pack only has the live record
+        let (_, _, _, length) = map.chunks().unwrap()[0];
+        assert_eq!(map.find_pack(new[0]).unwrap(), Some(length));
+        assert_eq!(map.find_pack(old).unwrap(), None);
+
+        let pack_file = PackOut::load(&mut remote.read(Typ::Pack, new[0]).unwrap(), &key).unwrap();
+        assert_eq!(pack_file.find_hash(contents[0]), Some(vec![7; 1024]));
+    }
+
+    #[test]
+    fn bad_threshold() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        for threshold in [-0.5, 1.5, f64::NAN] {
+            let options = Options::default();
+            assert!(repack(&key, &mut remote, &[], &options, threshold, Duration::ZERO).is_err());
+        }
+    }
+
+    #[test]
+    fn left_alone() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let options = Options::default();
+        let (snapshot, old, contents) = mostly_dead(&key, &remote, &options);
+
+        let run = |remote: &mut SqlVFS, grace| {
+            repack(
+                &key,
+                remote,
+                std::slice::from_ref(&snapshot),
+                &options,
+                0.5,
+                grace,
+            )
+            .unwrap()
+        };
+
+        // A checkpoint of an unfinished append still points to it
+        let checkpoint = Map::new().unwrap();
+        checkpoint
+            .insert_chunk(contents[2], 0, old, compress::Compression::Stored, 0)
+            .unwrap();
+        checkpoint
+            .unload(
+                &key,
+                &options.compression,
+                remote
+                    .write_multi_filename(Typ::Checkpoint, "C-test")
+                    .unwrap(),
+            )
+            .unwrap();
+        let report = run(&mut remote, Duration::ZERO);
+        assert_eq!((report.checkpointed, report.packs_removed), (1, 0));
+        remote.delete_filename(Typ::Checkpoint, "C-test").unwrap();
+
+        // Just written so its in the grace period
+        let report = run(&mut remote, Duration::from_hours(1));
+        assert_eq!((report.recent, report.packs_removed), (1, 0));
+        assert!(remote.stat(Typ::Pack, old).unwrap().is_some());
+    }
+}
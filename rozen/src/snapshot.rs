@@ -1,102 +1,1020 @@
 use std::collections::hash_map::Entry;
 use std::error::Error;
+use std::io;
 use std::io::{Read, Seek as _, SeekFrom, Write, copy};
-use std::path::Path;
-use zstd::stream::read::Decoder;
-use zstd::stream::read::Encoder;
+use std::num::NonZero;
+use std::path::{Component, Path};
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, sync_channel};
+use std::thread;
+use std::thread::ScopedJoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 
 use log::{debug, info, warn};
-use std::collections::HashMap;
-use std::fs::File;
+use serde::Deserialize;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::fs::create_dir_all;
+use std::os::unix::fs::MetadataExt as _;
+use std::os::unix::fs::PermissionsExt as _;
+use std::os::unix::fs::{lchown, symlink};
+use std::path::PathBuf;
 
+use nix::fcntl::AT_FDCWD;
+use nix::sys::stat::{Mode, SFlag, UtimensatFlags, major, makedev, minor, mknod, utimensat};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{gethostname, mkfifo};
+
+use crate::rcore::compress;
 use crate::rcore::crypto;
 use crate::rcore::hash;
 use crate::rcore::key;
+use crate::rcore::meta;
 
+use crate::rarc::pack::PackConfig;
 use crate::rarc::pack::PackOut;
 
 use crate::remote::Remote;
 use crate::remote::Typ;
 
+use crate::cas::Checkpoint;
 use crate::cas::ObjectFetch;
 use crate::cas::ObjectStore;
 
+use crate::ingest;
+use crate::ingest::Ingest;
+use crate::ingest::Kind;
+use crate::ingest::SharedRx;
+
 use crate::sql::Index;
 use crate::sql::Map;
+use crate::sql::WalkEntry;
+use crate::sql::diff_files;
 use crate::sql::walk_files;
 
+// Tuning for the backup pipeline, see `ingest` for the stages
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Pipeline {
+    // Number of threads reading the files, each file gets hashed + compressed + encrypted in a
+    // single read, 0 to use all available cores
+    pub hashers: usize,
+
+    // Depth of the bounded queue in front of each stage
+    pub queue: usize,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self {
+            hashers: 0,
+            queue: 64,
+        }
+    }
+}
+
+// What to do about a file that can't be read while taking a snapshot
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPolicy {
+    // Leave it out and record the path + error in the snapshot
+    #[default]
+    Skip,
+
+    // Fail the whole snapshot
+    Abort,
+}
+
+// Repository wide tunables for taking a snapshot
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct Options {
+    // Record fifos, sockets and device nodes too, directories and symlinks are always recorded
+    pub special_files: bool,
+
+    // Record extended attributes + acls, restoring some of them needs root
+    pub xattrs: bool,
+
+    // Packs to upload between checkpoints of an append in progress, 0 to never checkpoint. Every
+    // checkpoint uploads the whole map so far so this keeps it from happening on every pack
+    pub checkpoint_every: u64,
+
+    pub on_error: ErrorPolicy,
+
+    pub compression: compress::Policy,
+    pub pipeline: Pipeline,
+    pub pack: PackConfig,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            special_files: false,
+            xattrs: false,
+            checkpoint_every: 16,
+            on_error: ErrorPolicy::default(),
+            compression: compress::Policy::default(),
+            pipeline: Pipeline::default(),
+            pack: PackConfig::default(),
+        }
+    }
+}
+
+impl Options {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.compression.validate()?;
+        self.pack.validate()
+    }
+}
+
+// A source to back up, every root gets walked in order and the entries gets tagged with the
+// source's name in the index
+pub struct Source {
+    pub name: String,
+    pub walkers: Vec<ignore::Walk>,
+}
+
+// Which entries of a snapshot to restore, the patterns are globs matched against the path as it
+// was backed up and a pattern also matches everything under it, ie `home/docs` or `*.rs`
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    // Empty includes everything
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+// Tunables for restoring a snapshot
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    pub filter: Filter,
+
+    // Restore the recorded uid/gid as they are instead of mapping the recorded user/group names
+    // to the ids on this box, only applies when restoring as root
+    pub numeric_owner: bool,
+
+    // Carry on past files whose content doesn't match its hash instead of failing the restore,
+    // they get collected in the report instead
+    pub keep_going: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    // Files written out and verified
+    pub restored: usize,
+
+    // Files whose content didn't match its hash, these are not written out
+    pub mismatched: Vec<String>,
+}
+
+// How an entry differs between two snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Removed,
+
+    // Content, size, symlink target or device number changed, or the entry changed type
+    Modified,
+
+    // Same content but the mode, owner, mtime, etc changed
+    Metadata,
+}
+
+#[derive(Debug, Default)]
+pub struct Diff {
+    // Every entry that differs, in path order
+    pub changes: Vec<(Change, String)>,
+
+    // Size of the added + removed entries, and of the modified entries as of the newer snapshot
+    pub added_bytes: u64,
+    pub removed_bytes: u64,
+    pub modified_bytes: u64,
+}
+
+// Describes a snapshot, it is stored ahead of the index db so that listing the snapshots only
+// needs to read the start of each index
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Record {
+    // Filled in by `append`
+    pub hostname: Option<String>,
+    pub username: Option<String>,
+    pub start: Option<SystemTime>,
+    pub end: Option<SystemTime>,
+    pub version: String,
+
+    // Roots of the sources that got backed up
+    pub paths: Vec<String>,
+
+    // Unchanged files are carried over from the parent instead of getting hashed again
+    pub parent: Option<Keys>,
+
+    pub tags: Vec<String>,
+    pub description: Option<String>,
+
+    pub stats: Stats,
+}
+
+// What a backup run did, `append` returns it and stores it in the record
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Stats {
+    // Regular files that got walked, the skipped entries are whatever the walk could not read or
+    // is not backing up such as sockets
+    pub files_scanned: u64,
+    pub files_new: u64,
+    pub files_changed: u64,
+    pub files_unchanged: u64,
+    pub files_skipped: u64,
+
+    // Read while hashing, after compression + encryption, and the size of the uploaded packs
+    pub bytes_read: u64,
+    pub bytes_compressed: u64,
+    pub bytes_uploaded: u64,
+
+    // Files whose content was already claimed by another file in this snapshot
+    pub dedup_hits: u64,
+    pub packs_written: u64,
+
+    // Loading the parent, walking + storing the content, finalizing the packs + map
+    pub parent_elapsed: Duration,
+    pub ingest_elapsed: Duration,
+    pub finalize_elapsed: Duration,
+
+    // Entries left out because of an error, these are counted in the skipped files too
+    pub skipped: Vec<Skipped>,
+
+    // Files that kept changing while being read, what got stored is whatever the last read saw
+    pub unstable: Vec<String>,
+}
+
+// An entry left out of a snapshot and why
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    pub path: String,
+    pub reason: String,
+}
+
+// What `append` would store, from a dry run that only looks at the metadata
+#[derive(Debug, Default)]
+pub struct Plan {
+    // Files that would get hashed and stored in walk order, either `Added` or `Modified` since
+    // the parent along with their size
+    pub files: Vec<(Change, PathBuf, u64)>,
+
+    // Size of those files before dedup + compression, hardlinked files only counts once
+    pub new_bytes: u64,
+
+    pub files_unchanged: u64,
+    pub files_skipped: u64,
+    pub skipped: Vec<Skipped>,
+}
+
+impl Record {
+    fn begin(&mut self) -> Result<(), Box<dyn Error>> {
+        self.start = Some(SystemTime::now());
+        env!("CARGO_PKG_VERSION").clone_into(&mut self.version);
+        self.hostname = hostname()?;
+        self.username = uzers::get_current_username().and_then(|u| u.into_string().ok());
+        Ok(())
+    }
+}
+
+// What the records gets tagged with, for telling apart the snapshots of different hosts
+pub fn hostname() -> Result<Option<String>, Box<dyn Error>> {
+    Ok(gethostname()?.into_string().ok())
+}
+
+// Remote filenames of the index + map making up a snapshot
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Keys {
+    pub index: String,
+    pub map: String,
+}
+
+fn threads(count: usize) -> usize {
+    if count == 0 {
+        thread::available_parallelism().map_or(1, NonZero::get)
+    } else {
+        count
+    }
+}
+
 // TODO: can probs make the snapshot be strictly focused on snapshot concerns such as
 // - deciding what files needs to be stored in a snapshot
 // - deciding what file to skip/move/etc
-// - once it has decided that a file or block of data needs to go into a packfile it can then
-//      submit it to a queue that then get processed/packed up for shipping to the backend.
 // - Open question: what about backups that does delta/diff and chunking and all of that, would
 //      be instead submitting data/blocks. but could still see this section being only concerned
 //      with what data should be backed up
 //      then the queue can then manage "whole" or "chunked" or "chunked+delta" for processing
 //      before it ships it into the packfile possibly
+//
+// Files that are unchanged since the parent snapshot (same size, mtime, ctime and inode) are not
+// hashed again, their content gets carried over from the parent instead
 pub fn append<B: Remote, W: Write>(
     key: &key::MemKey,
     remote: &mut B,
     index_content: W,
     map_content: W,
-    walker: ignore::Walk,
-) -> Result<(), Box<dyn Error>> {
+    sources: Vec<Source>,
+    options: &Options,
+    mut record: Record,
+) -> Result<Stats, Box<dyn Error>> {
+    let (policy, pipeline) = (&options.compression, &options.pipeline);
     let index = Index::new()?;
-    let mut cas = ObjectStore::new(remote)?;
+    let mut phase = Instant::now();
 
-    {
-        for entry in walker {
-            match entry {
-                Ok(e) => {
-                    match e.file_type() {
-                        None => info!("NONE: {}", e.path().display()),
-                        Some(ft) => {
-                            if ft.is_file() {
-                                info!("COMP: {}", e.path().display());
-
-                                let meta = e.metadata()?;
-                                debug!("len: {:?}", meta.len());
-
-                                let mut file_data = File::open(e.path())?;
-
-                                // Hasher
-                                let content_hash = hash::hash(key, &mut file_data)?;
-                                file_data.seek(SeekFrom::Start(0))?;
-
-                                // TODO: need to make sure that each stage always calls
-                                // some form of finalize on its into_inner reader object
-                                // so that it can flush it up the pipeline into the output.
-
-                                let comp = Encoder::new(&mut file_data, 21)?;
-                                let mut enc = crypto::encrypt(key, comp)?;
-
-                                // Stream the data into the CAS system
-                                cas.append(content_hash, key, &mut enc, meta.len())?;
-
-                                // Load file info into index
-                                // Snapshot will be '<packfile-id>:<hash-id>' to pull out
-                                //  the content or can just be a list of <hash-id> then another
-                                //  list of <packfile-id> with <hash-id>s
-                                // TODO: better to just store content-id because it can be moved
-                                // around in packfile after compaction
-                                index.insert_file(e.path(), content_hash)?;
-                            } else {
-                                info!("SKIP: {}", e.path().display());
-                            }
-                        }
-                    }
+    record.begin()?;
+
+    let mut walkers = vec![];
+    for (id, source) in sources.into_iter().enumerate() {
+        index.insert_source(id, &source.name)?;
+        walkers.push(source.walkers);
+    }
+
+    let parent = match &record.parent {
+        Some(keys) => {
+            let parent_index =
+                Index::load(&mut remote.read_filename(Typ::Index, &keys.index)?, key)?;
+            let parent_map = Map::load(&mut remote.read_filename(Typ::Map, &keys.map)?, key)?;
+            Some((parent_index.fingerprints()?, parent_map))
+        }
+        None => None,
+    };
+    let fingerprints = parent.as_ref().map(|(fingerprints, _)| fingerprints);
+    let parent_elapsed = phase.elapsed();
+    phase = Instant::now();
+
+    let checkpoint = resume(key, remote, &record, options)?;
+    let name = checkpoint.name.clone();
+    let mut cas = ObjectStore::new(remote, &options.pack)?.with_checkpoint(checkpoint);
+    let skips = ingest::Skips::new(options.on_error);
+
+    let claimed = Mutex::new(HashSet::new());
+    let (walk_tx, walk_rx) = sync_channel(pipeline.queue);
+    let (write_tx, write_rx) = sync_channel(pipeline.queue);
+    let walk_rx = SharedRx::new(walk_rx);
+
+    // A stage that bails out closes the walk queue so the hashers stop instead of draining it and
+    // the walker gets hung up on instead of blocking on a full queue, the writer bailing out hangs
+    // up on the hashers which then closes it
+    let mut stats = thread::scope(|s| -> Result<Stats, Box<dyn Error>> {
+        let write_walk_tx = write_tx.clone();
+        let (special_files, skips, walk_rx) = (options.special_files, &skips, &walk_rx);
+        let mut handles = vec![s.spawn(move || {
+            let walked = ingest::walk(walkers, special_files, skips, &walk_tx, &write_walk_tx);
+            drop(walk_tx);
+            if walked.is_err() {
+                walk_rx.close();
+            }
+            walked
+        })];
+
+        for _ in 0..threads(pipeline.hashers) {
+            let write_tx = write_tx.clone();
+            let claimed = &claimed;
+            handles.push(s.spawn(move || {
+                let hashed = ingest::hash(
+                    key,
+                    policy,
+                    claimed,
+                    fingerprints,
+                    skips,
+                    walk_rx,
+                    &write_tx,
+                );
+                if hashed.is_err() {
+                    walk_rx.close();
                 }
-                Err(e) => warn!("ERRR: {e:?}"),
+                hashed
+            }));
+        }
+
+        // Only the workers holds on to the senders now so the writer is done once they are
+        drop(write_tx);
+        let written = write(
+            key,
+            &mut cas,
+            &index,
+            parent.as_ref(),
+            options.xattrs,
+            skips,
+            write_rx,
+        );
+        let joined: Vec<_> = handles.into_iter().map(ScopedJoinHandle::join).collect();
+
+        // Stages only error out with `Closed` after another stage bailed out, so report the
+        // actual cause which is the writer's error or else the first other error
+        let stats = written?;
+        let mut closed = None;
+        for result in joined {
+            match result.map_err(|_| "ingest thread panicked")? {
+                Err(e) if e.is::<ingest::Closed>() => closed = Some(e),
+                Err(e) => return Err(e),
+                Ok(()) => (),
             }
         }
+        closed.map_or(Ok(stats), |e| Err(e))
+    })?;
+    (stats.files_skipped, stats.skipped) =
+        skips.into_inner().map_err(|e| -> Box<dyn Error> { e })?;
+    stats.parent_elapsed = parent_elapsed;
+    stats.ingest_elapsed = phase.elapsed();
+    phase = Instant::now();
+
+    // Finalize the CAS
+    (stats.packs_written, stats.bytes_uploaded) = cas.finalize(map_content, key, policy)?;
+    stats.finalize_elapsed = phase.elapsed();
+
+    record.end = Some(SystemTime::now());
+    record.stats = stats.clone();
+    index.unload(
+        key,
+        policy,
+        toml::to_string(&record)?.as_bytes(),
+        index_content,
+    )?;
+
+    // The snapshot references every pack it uses now
+    remote.delete_filename(Typ::Checkpoint, &name)?;
+    Ok(stats)
+}
+
+// Picks up where an interrupted append on this host left off
+fn resume<B: Remote>(
+    key: &key::MemKey,
+    remote: &mut B,
+    record: &Record,
+    options: &Options,
+) -> Result<Checkpoint, Box<dyn Error>> {
+    let name = checkpoint_name(record);
+    let resumed = match remote.stat_filename(Typ::Checkpoint, &name)? {
+        Some(_) => Map::load(&mut remote.read_filename(Typ::Checkpoint, &name)?, key)?,
+        None => Map::new()?,
+    };
+
+    // Its packs may be gone since, only content that is still all there gets carried over
+    let packs: HashSet<_> = resumed
+        .chunks()?
+        .into_iter()
+        .map(|(_, _, pack, _)| pack)
+        .collect();
+    for pack in packs {
+        if remote.stat(Typ::Pack, pack)?.is_none() {
+            warn!("MISSING: {}", hash::to_hex(pack));
+            resumed.remove_contents(pack)?;
+        }
+    }
+
+    Ok(Checkpoint {
+        name,
+        every: options.checkpoint_every,
+        policy: options.compression.clone(),
+        resumed,
+        written: 0,
+    })
+}
+
+// One checkpoint per host, two appends running at once on the same host share it so the one
+// finishing first drops the other's checkpoint
+fn checkpoint_name(record: &Record) -> String {
+    format!("C-{}", record.hostname.as_deref().unwrap_or("unknown"))
+}
+
+// Dry run of `append`, the sources gets walked with the same excludes and compared against the
+// parent but nothing gets read or written to the remote
+pub fn plan<B: Remote>(
+    key: &key::MemKey,
+    remote: &mut B,
+    sources: Vec<Source>,
+    options: &Options,
+    parent: Option<&Keys>,
+) -> Result<Plan, Box<dyn Error>> {
+    let fingerprints = match parent {
+        Some(keys) => {
+            let parent_index =
+                Index::load(&mut remote.read_filename(Typ::Index, &keys.index)?, key)?;
+            Some(parent_index.fingerprints()?)
+        }
+        None => None,
+    };
+
+    let walkers = sources.into_iter().map(|source| source.walkers).collect();
+    let skips = ingest::Skips::new(options.on_error);
+    let (walk_tx, walk_rx) = sync_channel(options.pipeline.queue);
+    let (write_tx, write_rx) = sync_channel(options.pipeline.queue);
+
+    let mut plan = Plan::default();
+    thread::scope(|s| -> Result<(), Box<dyn Error>> {
+        let (special_files, skips) = (options.special_files, &skips);
+        let walker =
+            s.spawn(move || ingest::walk(walkers, special_files, skips, &walk_tx, &write_tx));
 
-        // Finalize the CAS
-        cas.finalize(map_content, key)?;
-        index.unload(key, index_content)?;
+        // Directories, symlinks and specials have no content to store, the scope joins it
+        s.spawn(move || write_rx.into_iter().count());
+
+        let planned = ingest::plan(fingerprints.as_ref(), skips, walk_rx, &mut plan);
+        let joined = walker.join();
+
+        // Same as `append` the walker errors out with a closed pipeline if planning bailed out
+        planned.map_err(|e| -> Box<dyn Error> { e })?;
+        joined
+            .map_err(|_| "ingest thread panicked")?
+            .map_err(|e| -> Box<dyn Error> { e })?;
         Ok(())
+    })?;
+    (plan.files_skipped, plan.skipped) = skips.into_inner().map_err(|e| -> Box<dyn Error> { e })?;
+    Ok(plan)
+}
+
+// Content that can only be read once such as a pipe
+pub enum Stream<R> {
+    // A single file holding everything that gets read
+    File { name: String, reader: R },
+
+    // Every entry of a tar along with its metadata
+    Tar(R),
+}
+
+// Single threaded counterpart of `append` for streams, there is no parent since there is nothing
+// to compare against before the content is read
+pub fn append_stream<B: Remote, R: Read, W: Write>(
+    key: &key::MemKey,
+    remote: &mut B,
+    index_content: W,
+    map_content: W,
+    stream: Stream<R>,
+    options: &Options,
+    mut record: Record,
+) -> Result<Stats, Box<dyn Error>> {
+    let policy = &options.compression;
+    let index = Index::new()?;
+    let mut phase = Instant::now();
+    record.begin()?;
+
+    let mut store = Store {
+        key,
+        policy,
+        cas: ObjectStore::new(remote, &options.pack)?,
+        index: &index,
+        claimed: HashSet::new(),
+        stats: Stats::default(),
+    };
+    match stream {
+        Stream::File { name, mut reader } => {
+            index.insert_source(0, "stdin")?;
+            store.file(Path::new(&name), stream_meta(), &mut reader)?;
+        }
+        Stream::Tar(reader) => {
+            index.insert_source(0, "tar")?;
+            import_tar(&mut store, reader)?;
+        }
+    }
+    let Store { cas, mut stats, .. } = store;
+    stats.ingest_elapsed = phase.elapsed();
+    phase = Instant::now();
+
+    (stats.packs_written, stats.bytes_uploaded) = cas.finalize(map_content, key, policy)?;
+    stats.finalize_elapsed = phase.elapsed();
+
+    record.end = Some(SystemTime::now());
+    record.stats = stats.clone();
+    index.unload(
+        key,
+        policy,
+        toml::to_string(&record)?.as_bytes(),
+        index_content,
+    )?;
+    Ok(stats)
+}
+
+// Writes the entries of a stream into the index + CAS as they are read
+struct Store<'a, B: Remote> {
+    key: &'a key::MemKey,
+    policy: &'a compress::Policy,
+    cas: ObjectStore<'a, B>,
+    index: &'a Index,
+    claimed: HashSet<hash::Hash>,
+    stats: Stats,
+}
+
+impl<B: Remote> Store<'_, B> {
+    // The content gets spooled so that it can be hashed before getting compressed + encrypted,
+    // only the first entry with this content gets it stored
+    fn file<R: Read>(
+        &mut self,
+        path: &Path,
+        mut meta: meta::Metadata,
+        reader: &mut R,
+    ) -> Result<(i64, hash::Hash, u64), Box<dyn Error>> {
+        info!("STRM: {}", path.display());
+        let mut spool = tempfile::spooled_tempfile(ingest::SPOOL_SIZE);
+        meta.size = copy(reader, &mut spool)?;
+        spool.seek(SeekFrom::Start(0))?;
+        let hash = hash::hash(self.key, &mut spool)?;
+        spool.seek(SeekFrom::Start(0))?;
+
+        self.stats.files_scanned += 1;
+        self.stats.files_new += 1;
+        self.stats.bytes_read += meta.size;
+        if self.claimed.insert(hash) {
+            let (comp, stored, mut data) =
+                ingest::seal(self.key, self.policy, path, meta.size, spool)
+                    .map_err(|e| -> Box<dyn Error> { e })?;
+            self.stats.bytes_compressed += stored;
+            self.cas
+                .append(hash, self.key, &mut data, meta.size, comp)?;
+        } else {
+            self.stats.dedup_hits += 1;
+        }
+
+        let id = self.index.insert_file(path, &meta, 0, hash, None)?;
+        Ok((id, hash, meta.size))
+    }
+}
+
+// Belongs to whoever runs the backup, and only readable by them since dumps tends to be sensitive
+fn stream_meta() -> meta::Metadata {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let sec = i64::try_from(now.as_secs()).unwrap_or(i64::MAX);
+    let nsec = i64::from(now.subsec_nanos());
+    let (uid, gid) = (uzers::get_current_uid(), uzers::get_current_gid());
+    let mut names = meta::Names::default();
+
+    meta::Metadata {
+        file_type: meta::FileType::File,
+        mode: 0o600,
+        size: 0,
+        mtime: sec,
+        mtime_nsec: nsec,
+        atime: sec,
+        atime_nsec: nsec,
+        ctime: sec,
+        ctime_nsec: nsec,
+        uid,
+        gid,
+        user: names.user(uid),
+        group: names.group(gid),
+        inode: 0,
+        device: 0,
+        rdev: 0,
+    }
+}
+
+// Hardlinks points at an earlier entry of the tar, they share its content and link group
+fn import_tar<B: Remote, R: Read>(
+    store: &mut Store<'_, B>,
+    reader: R,
+) -> Result<(), Box<dyn Error>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut files: HashMap<PathBuf, (i64, hash::Hash, u64)> = HashMap::new();
+    let mut groups: HashMap<i64, u64> = HashMap::new();
+    let mut leaves: HashSet<PathBuf> = HashSet::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let typ = entry.header().entry_type();
+
+        // Defaults for the entries that follows such as the commit of a `git archive`, there is
+        // nothing to record
+        if typ == tar::EntryType::XGlobalHeader {
+            continue;
+        }
+        let (Some(path), Some(file_type)) = (tar_path(&entry.path()?), tar_file_type(typ)) else {
+            let reason = match tar_file_type(typ) {
+                Some(_) => "path outside of the archive".to_owned(),
+                None => format!("unsupported entry type {typ:?}"),
+            };
+            tar_skip(&mut store.stats, &entry.path()?, reason);
+            continue;
+        };
+
+        // Anything under a symlink (or any other non-directory) would get restored wherever
+        // that points to, possibly outside of the target
+        if path.ancestors().skip(1).any(|a| leaves.contains(a)) {
+            tar_skip(&mut store.stats, &path, "not under a directory".to_owned());
+            continue;
+        }
+        if file_type != meta::FileType::Dir {
+            leaves.insert(path.clone());
+        }
+        let (meta, xattrs) = tar_meta(&mut entry, file_type)?;
+
+        let id = match typ {
+            tar::EntryType::Link => {
+                let target = entry.link_name()?.ok_or("link_name")?;
+                let &(first, hash, size) = tar_path(&target)
+                    .and_then(|target| files.get(&target))
+                    .ok_or_else(|| format!("Hardlink to an unknown entry: {target:?}"))?;
+
+                let next = groups.len() as u64;
+                let group = match groups.entry(first) {
+                    Entry::Occupied(e) => *e.get(),
+                    Entry::Vacant(e) => {
+                        store.index.set_link_group(first, next)?;
+                        *e.insert(next)
+                    }
+                };
+
+                store.stats.files_scanned += 1;
+                store.stats.files_new += 1;
+                store.stats.dedup_hits += 1;
+                let meta = meta::Metadata { size, ..meta };
+                store
+                    .index
+                    .insert_file(&path, &meta, 0, hash, Some(group))?
+            }
+            _ if file_type == meta::FileType::File => {
+                let file = store.file(&path, meta, &mut entry)?;
+                files.insert(path, file);
+                file.0
+            }
+            _ => {
+                let target = entry.link_name()?.map(Cow::into_owned);
+                store
+                    .index
+                    .insert_special(&path, &meta, 0, target.as_deref())?
+            }
+        };
+        store.index.insert_xattrs(id, &xattrs)?;
+    }
+    Ok(())
+}
+
+// Left out of the snapshot, it gets reported same as an entry `append` could not read
+fn tar_skip(stats: &mut Stats, path: &Path, reason: String) {
+    warn!("SKIP: {} - {reason}", path.display());
+    stats.files_skipped += 1;
+    stats.skipped.push(Skipped {
+        path: format!("{}", path.display()),
+        reason,
+    });
+}
+
+// Tars tend to have "./" in front of everything, a path that climbs out with ".." is refused since
+// it would end up outside of the target on restore
+fn tar_path(path: &Path) -> Option<PathBuf> {
+    if path.components().any(|c| c == Component::ParentDir) {
+        return None;
+    }
+    Some(
+        path.components()
+            .filter(|c| *c != Component::CurDir)
+            .collect(),
+    )
+}
+
+fn tar_file_type(typ: tar::EntryType) -> Option<meta::FileType> {
+    match typ {
+        tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::Link => {
+            Some(meta::FileType::File)
+        }
+        tar::EntryType::Directory => Some(meta::FileType::Dir),
+        tar::EntryType::Symlink => Some(meta::FileType::Symlink),
+        tar::EntryType::Fifo => Some(meta::FileType::Fifo),
+        tar::EntryType::Block => Some(meta::FileType::BlockDevice),
+        tar::EntryType::Char => Some(meta::FileType::CharDevice),
+        _ => None,
+    }
+}
+
+// The ustar header along with whatever the pax records has to add to it, the pax records wins
+fn tar_meta<R: Read>(
+    entry: &mut tar::Entry<'_, R>,
+    file_type: meta::FileType,
+) -> Result<(meta::Metadata, Vec<meta::Xattr>), Box<dyn Error>> {
+    let mut pax = BTreeMap::new();
+    if let Some(records) = entry.pax_extensions()? {
+        for record in records {
+            let record = record?;
+            pax.insert(record.key()?.to_owned(), record.value_bytes().to_vec());
+        }
+    }
+    let text = |key: &str| pax.get(key).map(|v| std::str::from_utf8(v)).transpose();
+    let header = entry.header();
+
+    let mtime = match text("mtime")? {
+        Some(time) => parse_pax_time(time)?,
+        None => (i64::try_from(header.mtime()?)?, 0),
+    };
+    let atime = text("atime")?.map(parse_pax_time).transpose()?;
+    let ctime = text("ctime")?.map(parse_pax_time).transpose()?;
+    let uid = match text("uid")? {
+        Some(uid) => uid.parse()?,
+        None => u32::try_from(header.uid()?)?,
+    };
+    let gid = match text("gid")? {
+        Some(gid) => gid.parse()?,
+        None => u32::try_from(header.gid()?)?,
+    };
+    let user = match text("uname")? {
+        Some(user) => Some(user),
+        None => header.username()?,
+    };
+    let group = match text("gname")? {
+        Some(group) => Some(group),
+        None => header.groupname()?,
+    };
+    // Plenty of writers leaves the device fields blank on everything else
+    let is_device = matches!(
+        file_type,
+        meta::FileType::BlockDevice | meta::FileType::CharDevice
+    );
+    let rdev = if is_device {
+        let major = header.device_major()?.ok_or("device_major")?;
+        let minor = header.device_minor()?.ok_or("device_minor")?;
+        makedev(major.into(), minor.into())
+    } else {
+        0
+    };
+
+    let xattrs = pax
+        .iter()
+        .filter_map(|(key, value)| {
+            let name = key.strip_prefix("SCHILY.xattr.")?;
+            Some((name.as_bytes().to_vec(), value.clone()))
+        })
+        .collect();
+    let meta = meta::Metadata {
+        file_type,
+        mode: header.mode()? & 0o7777,
+        size: header.size()?,
+        mtime: mtime.0,
+        mtime_nsec: mtime.1,
+        atime: atime.unwrap_or(mtime).0,
+        atime_nsec: atime.unwrap_or(mtime).1,
+        ctime: ctime.unwrap_or(mtime).0,
+        ctime_nsec: ctime.unwrap_or(mtime).1,
+        uid,
+        gid,
+        user: user.filter(|u| !u.is_empty()).map(str::to_owned),
+        group: group.filter(|g| !g.is_empty()).map(str::to_owned),
+        inode: 0,
+        device: 0,
+        rdev,
+    };
+    Ok((meta, xattrs))
+}
+
+// Only nanosecond precision is kept, the inverse of `pax_time`
+fn parse_pax_time(time: &str) -> Result<(i64, i64), Box<dyn Error>> {
+    let (negative, time) = time.strip_prefix('-').map_or((false, time), |t| (true, t));
+    let (sec, frac) = time.split_once('.').unwrap_or((time, ""));
+    let sec: i64 = sec.parse()?;
+    let nsec: i64 = format!("{:0<9}", frac.get(..9).unwrap_or(frac)).parse()?;
+
+    Ok(match (negative, nsec) {
+        (false, _) => (sec, nsec),
+        (true, 0) => (-sec, 0),
+        (true, _) => (-sec - 1, 1_000_000_000 - nsec),
+    })
+}
+
+// Only reads the start of the index, None if the snapshot predates the records
+pub fn record<R: Read>(
+    key: &key::MemKey,
+    index_content: &mut R,
+) -> Result<Option<Record>, Box<dyn Error>> {
+    Index::load_record(index_content, key)?
+        .map(|record| Ok(toml::from_str(std::str::from_utf8(&record)?)?))
+        .transpose()
+}
+
+// The single pack writer, takes ownership of the receiver so that it hangs up on the workers if
+// it bails out early, it sees every file so it tallies up the stats
+fn write<B: Remote>(
+    key: &key::MemKey,
+    cas: &mut ObjectStore<'_, B>,
+    index: &Index,
+    parent: Option<&(ingest::Parent, Map)>,
+    xattrs: bool,
+    skips: &ingest::Skips,
+    rx: Receiver<Ingest>,
+) -> Result<Stats, Box<dyn Error>> {
+    let mut names = meta::Names::default();
+    let mut links = HashMap::new();
+    let mut stats = Stats::default();
+
+    for Ingest { entry, kind } in rx {
+        let path = &entry.path;
+        let meta = meta::Metadata::from_fs(&entry.meta, &mut names);
+
+        // Hashed files are either new or changed since the parent, only their data regions got read
+        if let Kind::Index { size, .. } | Kind::Blob { size, .. } = &kind {
+            let known = parent.is_some_and(|(fingerprints, _)| {
+                fingerprints.contains_key(&format!("{}", path.display()))
+            });
+            if known {
+                stats.files_changed += 1;
+            } else {
+                stats.files_new += 1;
+            }
+            stats.bytes_read += size;
+        }
+        if entry.unstable {
+            stats.unstable.push(format!("{}", path.display()));
+        }
+
+        let id = match kind {
+            Kind::Index { hash, .. } => {
+                stats.dedup_hits += 1;
+
+                let group = link_group(&mut links, &entry.meta);
+                index.insert_file(path, &meta, entry.source, hash, group)?
+            }
+            Kind::Unchanged { hash } => {
+                stats.files_unchanged += 1;
+                cas.reuse(hash, &parent.ok_or("parent map")?.1)?;
+
+                let group = link_group(&mut links, &entry.meta);
+                index.insert_file(path, &meta, entry.source, hash, group)?
+            }
+            Kind::Special { target } => {
+                index.insert_special(path, &meta, entry.source, target.as_deref())?
+            }
+            Kind::Blob {
+                hash,
+                size,
+                stored,
+                comp,
+                mut data,
+            } => {
+                debug!("PACK: {} - len: {size:?} comp: {comp:?}", path.display());
+                stats.bytes_compressed += stored;
+
+                // Stream the data into the CAS system
+                cas.append(hash, key, &mut data, size, comp)?;
+
+                // Load file info into index
+                // TODO: better to just store content-id because it can be moved
+                // around in packfile after compaction
+                let group = link_group(&mut links, &entry.meta);
+                index.insert_file(path, &meta, entry.source, hash, group)?
+            }
+        };
+
+        index.insert_extents(id, &entry.extents)?;
+
+        // The entry is in already, only its xattrs gets left out
+        if xattrs {
+            match meta::read_xattrs(path) {
+                Ok(attrs) => index.insert_xattrs(id, &attrs)?,
+                Err(err) => skips
+                    .error(path, err)
+                    .map_err(|e| -> Box<dyn Error> { e })?,
+            }
+        }
+    }
+    stats.files_scanned = stats.files_new + stats.files_changed + stats.files_unchanged;
+    Ok(stats)
+}
+
+// Lays the content out over the data regions of a sparse file leaving the rest as holes, returns
+// where it went so it can be read back. Without extents the content simply starts at 0
+fn write_extents<R: Read>(
+    reader: &mut R,
+    file: &mut fs::File,
+    extents: &[meta::Extent],
+) -> Result<Vec<meta::Extent>, Box<dyn Error>> {
+    if extents.is_empty() {
+        return Ok(vec![(0, copy(reader, file)?)]);
+    }
+    for &(offset, len) in extents {
+        file.seek(SeekFrom::Start(offset))?;
+        if copy(&mut reader.take(len), file)? != len {
+            return Err("Content shorter than its extents".into());
+        }
+    }
+    Ok(extents.to_vec())
+}
+
+// The content of a sparse file with its holes put back in as zeros
+fn fill_holes(data: &[u8], extents: &[meta::Extent]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut ret = vec![];
+    let mut data = data;
+    for &(offset, len) in extents {
+        let len = usize::try_from(len)?;
+        if data.len() < len {
+            return Err("Content shorter than its extents".into());
+        }
+        ret.resize(usize::try_from(offset)?, 0);
+        ret.extend_from_slice(&data[..len]);
+        data = &data[len..];
     }
+    Ok(ret)
+}
+
+// Files with more than one link gets grouped by (device, inode) so that fetch can recreate them
+// as hardlinks instead of separate copies
+fn link_group(links: &mut HashMap<(u64, u64), u64>, meta: &fs::Metadata) -> Option<u64> {
+    if meta.nlink() < 2 {
+        return None;
+    }
+    let next = links.len() as u64;
+    Some(*links.entry((meta.dev(), meta.ino())).or_insert(next))
 }
 
 // TODO: hack of map_content_2 to deal with walk_files
@@ -108,33 +1026,385 @@ pub fn fetch<B: Remote, R: Read>(
     map_content: &mut R,
     map_content_2: &mut R,
     target: &Path,
-) -> Result<(), Box<dyn Error>> {
+    options: &RestoreOptions,
+) -> Result<RestoreReport, Box<dyn Error>> {
+    let mut report = RestoreReport::default();
     let map = Map::load(map_content, key)?;
     let mut cas = ObjectFetch::new(remote, map);
-    walk_files(index_content, map_content_2, key, |path, _, _, hash| {
-        let data = cas.get_content(key, hash)?.ok_or("get_content")?;
+    let mut owners = Owners::new(options);
+
+    // First restored path of each link group
+    let mut links: HashMap<u64, PathBuf> = HashMap::new();
+
+    // Writing the children bumps the mtime of a directory, and a read-only directory can't get
+    // any children, so the directory metadata gets applied once everything else is written
+    let mut dirs: Vec<(PathBuf, WalkEntry)> = vec![];
+
+    // Packs are only loaded as the content in them is needed so a filtered restore only
+    // downloads the packs of the matching entries
+    let filter = &options.filter;
+    walk_files(index_content, map_content_2, key, filter, |entry| {
+        // Absolute sources gets restored under the target as well
+        let path = Path::new(&entry.path);
+        let target_path = target.join(path.strip_prefix("/").unwrap_or(path));
+        let Some(content) = &entry.content else {
+            restore_special(entry, &target_path)?;
+            match entry.file_type {
+                meta::FileType::Dir => dirs.push((target_path, entry.clone())),
+                meta::FileType::Socket => (),
+                _ => restore_meta(entry, &target_path, &mut owners)?,
+            }
+            return Ok(());
+        };
+
+        if let Some(first) = entry.link_group.and_then(|g| links.get(&g)) {
+            info!("\tLINK: {first:?} - PATH: {target_path:?}");
+            create_dir_all(target_path.parent().ok_or("parent")?)?;
+            create_over(&target_path, |temp| fs::hard_link(first, temp))?;
+            return Ok(());
+        }
+
+        let (comp, data) = cas.get_content(key, content.hash)?.ok_or("get_content")?;
 
         // Verify the data
         let mut dec = crypto::decrypt(key, data)?;
-        let mut und = Decoder::new(&mut dec)?;
+        let mut und = compress::decompress(comp, &mut dec)?;
 
         // TODO: make this concurrent, for now, write to disk, then read and hash from disk.
-        let target_path = target.join(path);
-        create_dir_all(target_path.parent().ok_or("parent")?)?;
-        let mut target_file = File::create(&target_path)?;
-        copy(&mut und, &mut target_file)?;
-        target_file.sync_data()?;
+        // The content goes into a temp file next to the target that only gets renamed into place
+        // once it is verified, so a bad restore never clobbers what is already there
+        let parent = target_path.parent().ok_or("parent")?;
+        create_dir_all(parent)?;
+        let mut temp = tempfile::Builder::new()
+            .prefix(".rozen-")
+            .tempfile_in(parent)?;
+        let extents = write_extents(&mut und, temp.as_file_mut(), &entry.extents)?;
 
-        let mut hash_file = File::open(&target_path)?;
-        let content_hash = hash::hash(key, &mut hash_file)?;
+        // Whatever is past the content is a hole
+        if temp.as_file().metadata()?.len() < entry.size {
+            temp.as_file().set_len(entry.size)?;
+        }
+        temp.as_file().sync_data()?;
+        let mut written = meta::ExtentReader::new(temp.as_file_mut(), &extents);
+        let content_hash = hash::with_extents(key, hash::hash(key, &mut written)?, &entry.extents);
 
-        let is_same = hash == content_hash;
+        let is_same = content.hash == content_hash;
         info!("\tSAME: {is_same:5} - PATH: {target_path:?}");
+
+        if !is_same {
+            if !options.keep_going {
+                return Err(format!("Hash mismatch: {}", entry.path).into());
+            }
+            warn!("MISMATCH: {}", entry.path);
+            report.mismatched.push(entry.path.clone());
+            return Ok(());
+        }
+        temp.persist(&target_path)?;
+        report.restored += 1;
+
+        restore_meta(entry, &target_path, &mut owners)?;
+
+        if let Some(group) = entry.link_group {
+            links.insert(group, target_path);
+        }
         Ok(())
     })?;
+
+    // Deepest first, not that it matters since the times of a directory are left alone when
+    // the metadata of a child changes
+    for (path, entry) in dirs.iter().rev() {
+        restore_meta(entry, path, &mut owners)?;
+    }
+    Ok(report)
+}
+
+// Recreate an entry that has no content, the metadata is applied separately
+fn restore_special(entry: &WalkEntry, path: &Path) -> Result<(), Box<dyn Error>> {
+    info!("\tSPEC: {:?} - PATH: {path:?}", entry.file_type);
+    create_dir_all(path.parent().ok_or("parent")?)?;
+
+    let mode = Mode::from_bits_truncate(entry.permission);
+    let node = |kind| move |temp: &Path| Ok(mknod(temp, kind, mode, entry.rdev)?);
+    match entry.file_type {
+        meta::FileType::Dir => create_dir_all(path)?,
+        meta::FileType::Symlink => {
+            let target = entry.target.as_ref().ok_or("link_target")?;
+            create_over(path, |temp| symlink(target, temp))?;
+        }
+        meta::FileType::Fifo => create_over(path, |temp| Ok(mkfifo(temp, mode)?))?,
+        meta::FileType::BlockDevice => create_over(path, node(SFlag::S_IFBLK))?,
+        meta::FileType::CharDevice => create_over(path, node(SFlag::S_IFCHR))?,
+        // Sockets only exists while something is listening on it, nothing to restore
+        meta::FileType::Socket => info!("\tSKIP: {path:?}"),
+        meta::FileType::File => return Err(format!("No content for: {path:?}").into()),
+    }
     Ok(())
 }
 
+// Creates the entry under a temp name next to the target then renames it into place, so whatever
+// is already there gets replaced the same way a restored file replaces it
+fn create_over<F>(path: &Path, create: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(&Path) -> io::Result<()>,
+{
+    let temp = tempfile::Builder::new()
+        .prefix(".rozen-")
+        .make_in(path.parent().ok_or("parent")?, create)?;
+    temp.persist(path)?;
+    Ok(())
+}
+
+// Who the restored entries should belong to, only root can give files away so otherwise
+// everything ends up owned by whoever runs the restore
+struct Owners {
+    chown: bool,
+    numeric: bool,
+    ids: meta::Ids,
+}
+
+impl Owners {
+    fn new(options: &RestoreOptions) -> Self {
+        Self {
+            chown: uzers::get_effective_uid() == 0,
+            numeric: options.numeric_owner,
+            ids: meta::Ids::default(),
+        }
+    }
+
+    // The recorded names wins over the recorded ids unless asked otherwise, a name that doesn't
+    // exist here falls back to the id
+    fn of(&mut self, entry: &WalkEntry) -> (u32, u32) {
+        if self.numeric {
+            return (entry.uid, entry.gid);
+        }
+        let uid = entry.user.as_deref().and_then(|u| self.ids.uid(u));
+        let gid = entry.group.as_deref().and_then(|g| self.ids.gid(g));
+        (uid.unwrap_or(entry.uid), gid.unwrap_or(entry.gid))
+    }
+}
+
+// The order matters, chown clears the suid/sgid bits and file capabilities so it goes first,
+// and the times goes last since everything else bumps them
+fn restore_meta(entry: &WalkEntry, path: &Path, owners: &mut Owners) -> Result<(), Box<dyn Error>> {
+    let is_symlink = entry.file_type == meta::FileType::Symlink;
+
+    if owners.chown {
+        let (uid, gid) = owners.of(entry);
+        lchown(path, Some(uid), Some(gid))?;
+    }
+
+    // Symlinks have no mode of their own, and no setting xattrs on a dangling symlink and user
+    // xattrs are not allowed on symlinks
+    if !is_symlink {
+        fs::set_permissions(path, fs::Permissions::from_mode(entry.permission))?;
+        meta::write_xattrs(path, &entry.xattrs)?;
+    }
+
+    let time = |(sec, nsec): (i64, i64)| TimeSpec::new(sec, nsec);
+    utimensat(
+        AT_FDCWD,
+        path,
+        &time(entry.atime),
+        &time(entry.mtime),
+        UtimensatFlags::NoFollowSymlink,
+    )?;
+    Ok(())
+}
+
+// Streams the snapshot as a pax tar, the content gets verified in memory so nothing touches the
+// local disk, sockets can't go into a tar so they are left out
+pub fn export_tar<B: Remote, R: Read, W: Write>(
+    key: &key::MemKey,
+    remote: &mut B,
+    index_content: &mut R,
+    map_content: &mut R,
+    map_content_2: &mut R,
+    writer: W,
+) -> Result<W, Box<dyn Error>> {
+    let map = Map::load(map_content, key)?;
+    let mut cas = ObjectFetch::new(remote, map);
+    let mut builder = tar::Builder::new(writer);
+
+    // First exported path of each link group, the rest of the group are hardlinks to it
+    let mut links: HashMap<u64, PathBuf> = HashMap::new();
+
+    let filter = Filter::default();
+    walk_files(index_content, map_content_2, key, &filter, |entry| {
+        // Absolute sources are exported relative to the root of the tar
+        let path = Path::new(&entry.path);
+        let path = match path.strip_prefix("/").unwrap_or(path) {
+            p if p.as_os_str().is_empty() => Path::new("."),
+            p => p,
+        };
+        let Some(entry_type) = tar_type(entry.file_type) else {
+            info!("\tSKIP: {path:?}");
+            return Ok(());
+        };
+
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(entry_type);
+        let mut pax = tar_header(entry, path, &mut header)?;
+
+        let first = entry.link_group.and_then(|g| links.get(&g));
+        let data = match (&entry.content, first) {
+            (Some(_), Some(first)) => {
+                header.set_entry_type(tar::EntryType::Link);
+                tar_link(&mut header, &mut pax, first);
+                vec![]
+            }
+            (Some(content), None) => {
+                let (comp, data) = cas.get_content(key, content.hash)?.ok_or("get_content")?;
+                let mut dec = crypto::decrypt(key, data)?;
+                let mut und = compress::decompress(comp, &mut dec)?;
+
+                let mut data = vec![];
+                copy(&mut und, &mut data)?;
+                let content_hash = hash::hash(key, &mut &data[..])?;
+                if hash::with_extents(key, content_hash, &entry.extents) != content.hash {
+                    return Err(format!("Hash mismatch: {}", entry.path).into());
+                }
+
+                // Tar has no holes, they get filled in with zeros
+                if !entry.extents.is_empty() {
+                    data = fill_holes(&data, &entry.extents)?;
+                }
+                if (data.len() as u64) < entry.size {
+                    data.resize(usize::try_from(entry.size)?, 0);
+                }
+
+                if let Some(group) = entry.link_group {
+                    links.insert(group, path.to_path_buf());
+                }
+                data
+            }
+            (None, _) => vec![],
+        };
+        info!("\tTAR: {path:?}");
+
+        if !pax.is_empty() {
+            let mut ext = tar::Header::new_ustar();
+            ext.set_entry_type(tar::EntryType::XHeader);
+            ext.set_path("PaxHeader")?;
+            ext.set_mode(0o644);
+            ext.set_size(pax.len() as u64);
+            ext.set_cksum();
+            builder.append(&ext, &pax[..])?;
+        }
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        builder.append(&header, &data[..])?;
+        Ok(())
+    })?;
+    Ok(builder.into_inner()?)
+}
+
+fn tar_type(file_type: meta::FileType) -> Option<tar::EntryType> {
+    match file_type {
+        meta::FileType::File => Some(tar::EntryType::Regular),
+        meta::FileType::Dir => Some(tar::EntryType::Directory),
+        meta::FileType::Symlink => Some(tar::EntryType::Symlink),
+        meta::FileType::Fifo => Some(tar::EntryType::Fifo),
+        meta::FileType::BlockDevice => Some(tar::EntryType::Block),
+        meta::FileType::CharDevice => Some(tar::EntryType::Char),
+        meta::FileType::Socket => None,
+    }
+}
+
+// The ustar header only fits short names and whole second times, whatever doesn't fit goes into
+// the pax records that precedes the entry
+fn tar_header(
+    entry: &WalkEntry,
+    path: &Path,
+    header: &mut tar::Header,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut pax = vec![];
+
+    if header.set_path(path).is_err() {
+        let path = path.as_os_str().as_encoded_bytes();
+        pax_record(&mut pax, "path", path);
+        truncate_into(&mut header.as_old_mut().name, path);
+    }
+    if let Some(target) = &entry.target {
+        tar_link(header, &mut pax, Path::new(target));
+    }
+
+    // Ids past what the octal fields holds
+    header.set_mode(entry.permission);
+    if entry.uid > 0o7_777_777 {
+        pax_record(&mut pax, "uid", entry.uid.to_string().as_bytes());
+    } else {
+        header.set_uid(u64::from(entry.uid));
+    }
+    if entry.gid > 0o7_777_777 {
+        pax_record(&mut pax, "gid", entry.gid.to_string().as_bytes());
+    } else {
+        header.set_gid(u64::from(entry.gid));
+    }
+    if let Some(user) = &entry.user
+        && header.set_username(user).is_err()
+    {
+        pax_record(&mut pax, "uname", user.as_bytes());
+    }
+    if let Some(group) = &entry.group
+        && header.set_groupname(group).is_err()
+    {
+        pax_record(&mut pax, "gname", group.as_bytes());
+    }
+
+    header.set_mtime(u64::try_from(entry.mtime.0).unwrap_or(0));
+    pax_record(&mut pax, "mtime", pax_time(entry.mtime).as_bytes());
+    pax_record(&mut pax, "atime", pax_time(entry.atime).as_bytes());
+
+    if matches!(
+        entry.file_type,
+        meta::FileType::BlockDevice | meta::FileType::CharDevice
+    ) {
+        header.set_device_major(u32::try_from(major(entry.rdev))?)?;
+        header.set_device_minor(u32::try_from(minor(entry.rdev))?)?;
+    }
+
+    for (name, value) in &entry.xattrs {
+        let name = format!("SCHILY.xattr.{}", String::from_utf8_lossy(name));
+        pax_record(&mut pax, &name, value);
+    }
+    Ok(pax)
+}
+
+fn tar_link(header: &mut tar::Header, pax: &mut Vec<u8>, target: &Path) {
+    if header.set_link_name(target).is_err() {
+        let target = target.as_os_str().as_encoded_bytes();
+        pax_record(pax, "linkpath", target);
+        truncate_into(&mut header.as_old_mut().linkname, target);
+    }
+}
+
+// Readers that don't know pax still gets the start of the name
+fn truncate_into(field: &mut [u8], value: &[u8]) {
+    let len = field.len().min(value.len());
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+// A pax record is "<length> <key>=<value>\n" where the length counts its own digits
+fn pax_record(pax: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    pax.extend_from_slice(format!("{len} {key}=").as_bytes());
+    pax.extend_from_slice(value);
+    pax.push(b'\n');
+}
+
+// Seconds with the nanoseconds as a fraction, a negative time counts down from the epoch
+fn pax_time((sec, nsec): (i64, i64)) -> String {
+    if sec < 0 && nsec > 0 {
+        format!("-{}.{:09}", -(sec + 1), 1_000_000_000 - nsec)
+    } else {
+        format!("{sec}.{nsec:09}")
+    }
+}
+
 pub fn verify<B: Remote, R: Read>(
     key: &key::MemKey,
     remote: &mut B,
@@ -146,36 +1416,1227 @@ pub fn verify<B: Remote, R: Read>(
 
     // Dump the sqlite db data so we can view what it is
     println!("VERIFYING:");
-    walk_files(index_content, map_content, key, |path, perm, pack, hash| {
-        println!("\tHASH: {hash:?}");
-        println!("\t\tPACK: {pack:?}");
+    let filter = Filter::default();
+    walk_files(index_content, map_content, key, &filter, |entry| {
+        let Some(content) = &entry.content else {
+            println!("\tPATH: {:?}", entry.path);
+            println!("\tTYPE: {:?}", entry.file_type);
+            return Ok(());
+        };
 
-        // Find or load the packfile
-        if let Entry::Vacant(e) = pack_cache.entry(pack) {
-            let mut pack_read = remote.read(Typ::Pack, pack)?;
-            let pack_file = PackOut::load(&mut pack_read, key)?;
+        println!("\tHASH: {:?}", content.hash);
+        println!("\t\tPACK: {:?}", content.packs);
 
-            e.insert(pack_file);
+        // TODO: make this into a streaming read but for now copy data
+        let mut data: Vec<u8> = Vec::new();
+        for pack in &content.packs {
+            // Find or load the packfile
+            if let Entry::Vacant(e) = pack_cache.entry(*pack) {
+                let mut pack_read = remote.read(Typ::Pack, *pack)?;
+                let pack_file = PackOut::load(&mut pack_read, key)?;
 
-            // TODO: make this into a streaming read but for now copy data
-            let data: Vec<u8> = pack_cache
-                .get(&pack)
-                .ok_or("pack_get")?
-                .find_hash(hash)
-                .ok_or("hash_clone")?;
+                e.insert(pack_file);
+            }
 
-            // Process the data
-            let mut dec = crypto::decrypt(key, &data[..])?;
-            let mut und = Decoder::new(&mut dec)?;
-            let content_hash = hash::hash(key, &mut und)?;
+            data.extend(
+                pack_cache
+                    .get(pack)
+                    .ok_or("pack_get")?
+                    .find_hash(content.hash)
+                    .ok_or("hash_clone")?,
+            );
+        }
 
-            println!("\tPATH: {path:?}");
-            println!("\tPERM: {perm:?}");
+        // Process the data
+        let mut dec = crypto::decrypt(key, &data[..])?;
+        let mut und = compress::decompress(content.compression, &mut dec)?;
+        let content_hash = hash::with_extents(key, hash::hash(key, &mut und)?, &entry.extents);
 
-            let is_same = hash == content_hash;
-            println!("\tSAME: {is_same:5}");
-        }
+        println!("\tPATH: {:?}", entry.path);
+        println!("\tPERM: {:?}", entry.permission);
+
+        let is_same = content.hash == content_hash;
+        println!("\tSAME: {is_same:5}");
         Ok(())
     })?;
     Ok(())
 }
+
+// Compares the indexes of two snapshots, the maps (and packs) are not needed for this
+pub fn diff<R: Read>(
+    key: &key::MemKey,
+    old_index: &mut R,
+    new_index: &mut R,
+) -> Result<Diff, Box<dyn Error>> {
+    let mut diff = Diff::default();
+    diff_files(old_index, new_index, key, |change, path, size| {
+        match change {
+            Change::Added => diff.added_bytes += size,
+            Change::Removed => diff.removed_bytes += size,
+            Change::Modified => diff.modified_bytes += size,
+            Change::Metadata => (),
+        }
+        diff.changes.push((change, path));
+        Ok(())
+    })?;
+    Ok(diff)
+}
+
+#[cfg(all(test, feature = "sql"))]
+mod test_snapshot {
+    use super::*;
+
+    use crate::rarc::pack::PackBuilder;
+    use crate::remote::sql::SqlVFS;
+
+    fn roundtrip(source: &Path, target: &Path, options: &Options) -> Stats {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+
+        let (mut index, mut map) = (vec![], vec![]);
+        let stats = append(
+            &key,
+            &mut remote,
+            &mut index,
+            &mut map,
+            vec![Source {
+                name: "test".to_owned(),
+                walkers: vec![
+                    ignore::WalkBuilder::new(source)
+                        .standard_filters(false)
+                        .build(),
+                ],
+            }],
+            options,
+            Record::default(),
+        )
+        .unwrap();
+
+        fetch(
+            &key,
+            &mut remote,
+            &mut &index[..],
+            &mut &map[..],
+            &mut &map[..],
+            target,
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+        stats
+    }
+
+    #[test]
+    fn special_entries() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        fs::write(source.path().join("file"), b"Hello World!").unwrap();
+        create_dir_all(source.path().join("empty")).unwrap();
+        symlink("file", source.path().join("link")).unwrap();
+        mkfifo(&source.path().join("fifo"), Mode::from_bits_truncate(0o644)).unwrap();
+
+        let options = Options {
+            special_files: true,
+            ..Options::default()
+        };
+        // Restoring again replaces what the first restore left behind
+        roundtrip(source.path(), target.path(), &options);
+        roundtrip(source.path(), target.path(), &options);
+
+        let restored = target.path().join(source.path().strip_prefix("/").unwrap());
+        assert_eq!(fs::read(restored.join("file")).unwrap(), b"Hello World!");
+        assert!(restored.join("empty").is_dir());
+        assert_eq!(
+            fs::read_link(restored.join("link")).unwrap(),
+            Path::new("file")
+        );
+        assert!(std::os::unix::fs::FileTypeExt::is_fifo(
+            &fs::symlink_metadata(restored.join("fifo"))
+                .unwrap()
+                .file_type()
+        ));
+    }
+
+    #[test]
+    fn hardlinks() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        fs::write(source.path().join("a"), b"Hello World!").unwrap();
+        fs::hard_link(source.path().join("a"), source.path().join("b")).unwrap();
+        fs::write(source.path().join("c"), b"Hello World!").unwrap();
+
+        // Restoring again replaces what the first restore left behind
+        roundtrip(source.path(), target.path(), &Options::default());
+        roundtrip(source.path(), target.path(), &Options::default());
+
+        let restored = target.path().join(source.path().strip_prefix("/").unwrap());
+        let ino = |name| fs::metadata(restored.join(name)).unwrap().ino();
+        assert_eq!(ino("a"), ino("b"));
+        assert_ne!(ino("a"), ino("c"));
+        assert_eq!(fs::read(restored.join("b")).unwrap(), b"Hello World!");
+    }
+
+    #[test]
+    fn xattrs() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        // Not every filesystem the tests run on has user xattrs
+        let file = source.path().join("file");
+        fs::write(&file, b"Hello World!").unwrap();
+        if xattr::set(&file, "user.rozen", b"file").is_err() {
+            return;
+        }
+        xattr::set(source.path(), "user.rozen", b"dir").unwrap();
+
+        let options = Options {
+            xattrs: true,
+            ..Options::default()
+        };
+        roundtrip(source.path(), target.path(), &options);
+
+        let restored = target.path().join(source.path().strip_prefix("/").unwrap());
+        let get = |path: PathBuf| xattr::get(path, "user.rozen").unwrap();
+        assert_eq!(get(restored.join("file")), Some(b"file".to_vec()));
+        assert_eq!(get(restored), Some(b"dir".to_vec()));
+    }
+
+    #[test]
+    fn sparse_files() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        let sparse = source.path().join("sparse");
+        let mut file = fs::File::create(&sparse).unwrap();
+        file.set_len(8 << 20).unwrap();
+        file.seek(SeekFrom::Start(1 << 20)).unwrap();
+        file.write_all(b"Hello World!").unwrap();
+        drop(file);
+        fs::File::create(source.path().join("holes"))
+            .unwrap()
+            .set_len(1 << 20)
+            .unwrap();
+
+        // Same data further into the file
+        let moved = source.path().join("moved");
+        let mut file = fs::File::create(&moved).unwrap();
+        file.set_len(8 << 20).unwrap();
+        file.seek(SeekFrom::Start(2 << 20)).unwrap();
+        file.write_all(b"Hello World!").unwrap();
+        drop(file);
+
+        let stats = roundtrip(source.path(), target.path(), &Options::default());
+
+        let restored = target.path().join(source.path().strip_prefix("/").unwrap());
+        assert_eq!(
+            fs::read(restored.join("sparse")).unwrap(),
+            fs::read(&sparse).unwrap()
+        );
+        assert_eq!(
+            fs::read(restored.join("moved")).unwrap(),
+            fs::read(&moved).unwrap()
+        );
+        assert_eq!(fs::read(restored.join("holes")).unwrap(), vec![0; 1 << 20]);
+
+        // Only a filesystem with holes keeps them around, then only the data gets read and the
+        // layout keeps the moved data from deduping with the other file
+        let allocated = |path: &Path| fs::metadata(path).unwrap().blocks() * 512;
+        if allocated(&sparse) < 8 << 20 {
+            assert!(allocated(&restored.join("sparse")) < 8 << 20);
+            assert!(allocated(&restored.join("holes")) < 1 << 20);
+            assert!(stats.bytes_read < 1 << 20);
+            assert_eq!(stats.dedup_hits, 0);
+        }
+    }
+
+    #[test]
+    fn unchanged_parent() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        let backup = |remote: &mut SqlVFS, name: &str, parent: Option<&Keys>| {
+            let keys = Keys {
+                index: format!("I-{name}"),
+                map: format!("M-{name}"),
+            };
+            append(
+                &key,
+                remote,
+                remote
+                    .write_multi_filename(Typ::Index, &keys.index)
+                    .unwrap(),
+                remote.write_multi_filename(Typ::Map, &keys.map).unwrap(),
+                vec![Source {
+                    name: "test".to_owned(),
+                    walkers: vec![
+                        ignore::WalkBuilder::new(source.path())
+                            .standard_filters(false)
+                            .build(),
+                    ],
+                }],
+                &Options::default(),
+                Record {
+                    parent: parent.cloned(),
+                    ..Record::default()
+                },
+            )
+            .unwrap();
+            keys
+        };
+        let load_map = |remote: &mut SqlVFS, keys: &Keys| {
+            Map::load(
+                &mut remote.read_filename(Typ::Map, &keys.map).unwrap(),
+                &key,
+            )
+            .unwrap()
+        };
+        let hash_of = |data: &[u8]| hash::hash(&key, &mut &data[..]).unwrap();
+
+        fs::write(source.path().join("a"), b"Hello World!").unwrap();
+        fs::write(source.path().join("b"), b"Hello").unwrap();
+        let first = backup(&mut remote, "1", None);
+
+        fs::write(source.path().join("b"), b"Hello Changed").unwrap();
+        let second = backup(&mut remote, "2", Some(&first));
+
+        // The record ahead of the index remembers where the snapshot came from
+        let record = record(
+            &key,
+            &mut remote.read_filename(Typ::Index, &second.index).unwrap(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(record.parent, Some(first.clone()));
+        assert_eq!(record.version, env!("CARGO_PKG_VERSION"));
+        assert!(record.start <= record.end);
+
+        // Only the changed file got read and stored again
+        let stats = &record.stats;
+        assert_eq!(
+            (stats.files_scanned, stats.files_new, stats.files_changed),
+            (2, 0, 1)
+        );
+        assert_eq!((stats.files_unchanged, stats.files_skipped), (1, 0));
+        assert_eq!(stats.bytes_read, b"Hello Changed".len() as u64);
+        assert_eq!(stats.packs_written, 1);
+        assert!(stats.bytes_compressed > 0 && stats.bytes_uploaded > stats.bytes_compressed);
+
+        // The unchanged file points at the same pack, the changed one got stored again
+        let (first_map, second_map) = (
+            load_map(&mut remote, &first),
+            load_map(&mut remote, &second),
+        );
+        let a = hash_of(b"Hello World!");
+        assert_eq!(
+            first_map.find_packs(a).unwrap(),
+            second_map.find_packs(a).unwrap()
+        );
+        assert_eq!(
+            second_map
+                .find_packs(hash_of(b"Hello Changed"))
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let mut index = remote.read_filename(Typ::Index, &second.index).unwrap();
+        let mut map = remote.read_filename(Typ::Map, &second.map).unwrap();
+        let mut map_2 = remote.read_filename(Typ::Map, &second.map).unwrap();
+        fetch(
+            &key,
+            &mut remote,
+            &mut index,
+            &mut map,
+            &mut map_2,
+            target.path(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+
+        let restored = target.path().join(source.path().strip_prefix("/").unwrap());
+        assert_eq!(fs::read(restored.join("a")).unwrap(), b"Hello World!");
+        assert_eq!(fs::read(restored.join("b")).unwrap(), b"Hello Changed");
+    }
+
+    #[test]
+    fn dry_run() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let source = tempfile::tempdir().unwrap();
+
+        let sources = || {
+            vec![Source {
+                name: "test".to_owned(),
+                walkers: vec![
+                    ignore::WalkBuilder::new(source.path())
+                        .standard_filters(false)
+                        .build(),
+                ],
+            }]
+        };
+        let keys = Keys {
+            index: "I-1".to_owned(),
+            map: "M-1".to_owned(),
+        };
+
+        fs::write(source.path().join("a"), b"Hello World!").unwrap();
+        fs::write(source.path().join("b"), b"Hello").unwrap();
+        let index = remote.write_multi_filename(Typ::Index, &keys.index);
+        let map = remote.write_multi_filename(Typ::Map, &keys.map);
+        append(
+            &key,
+            &mut remote,
+            index.unwrap(),
+            map.unwrap(),
+            sources(),
+            &Options::default(),
+            Record::default(),
+        )
+        .unwrap();
+        let packs = remote.list_keys(Typ::Pack).unwrap().count();
+
+        fs::write(source.path().join("b"), b"Hello Changed").unwrap();
+        fs::write(source.path().join("c"), b"New").unwrap();
+        fs::hard_link(source.path().join("c"), source.path().join("d")).unwrap();
+
+        let planned = plan(
+            &key,
+            &mut remote,
+            sources(),
+            &Options::default(),
+            Some(&keys),
+        )
+        .unwrap();
+        let root = source.path();
+        let mut files: Vec<_> = planned
+            .files
+            .iter()
+            .map(|(change, path, size)| (*change, path.strip_prefix(root).unwrap(), *size))
+            .collect();
+        files.sort_by_key(|(_, path, _)| path.to_path_buf());
+        assert_eq!(
+            files,
+            vec![
+                (Change::Modified, Path::new("b"), 13),
+                (Change::Added, Path::new("c"), 3),
+                (Change::Added, Path::new("d"), 3),
+            ]
+        );
+        assert_eq!(planned.new_bytes, 16);
+        assert_eq!((planned.files_unchanged, planned.files_skipped), (1, 0));
+
+        // Without a parent everything is new, and nothing got written either way
+        let planned = plan(&key, &mut remote, sources(), &Options::default(), None).unwrap();
+        assert_eq!(planned.files.len(), 4);
+        assert!(
+            planned
+                .files
+                .iter()
+                .all(|(change, _, _)| *change == Change::Added)
+        );
+        assert_eq!(remote.list_keys(Typ::Pack).unwrap().count(), packs);
+        assert_eq!(remote.list_keys(Typ::Index).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn resume_checkpoint() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        // Every file gets its own pack so its done uploading as soon as its stored
+        let options = Options {
+            pack: PackConfig {
+                target_size: 4 * 1024,
+                small_file: 4,
+                max_size: 8 * 1024,
+            },
+            ..Options::default()
+        };
+
+        let path = source.path().join("a");
+        let data = b"Hello World!";
+        fs::write(&path, data).unwrap();
+
+        // What an append on this host that died right after storing `a` leaves behind
+        let mut record = Record::default();
+        record.begin().unwrap();
+        {
+            let (policy, size) = (&options.compression, data.len() as u64);
+            let (comp, _, mut sealed) = ingest::seal(&key, policy, &path, size, &data[..]).unwrap();
+            let checkpoint = Checkpoint {
+                name: checkpoint_name(&record),
+                every: 1,
+                policy: policy.clone(),
+                resumed: Map::new().unwrap(),
+                written: 0,
+            };
+            let mut cas = ObjectStore::new(&remote, &options.pack)
+                .unwrap()
+                .with_checkpoint(checkpoint);
+            let hash = hash::hash(&key, &mut &data[..]).unwrap();
+            cas.append(hash, &key, &mut sealed, size, comp).unwrap();
+        }
+        assert_eq!(remote.list_keys(Typ::Pack).unwrap().count(), 1);
+        assert_eq!(remote.list_keys(Typ::Checkpoint).unwrap().count(), 1);
+
+        let (mut index, mut map) = (vec![], vec![]);
+        let stats = append(
+            &key,
+            &mut remote,
+            &mut index,
+            &mut map,
+            vec![Source {
+                name: "test".to_owned(),
+                walkers: vec![
+                    ignore::WalkBuilder::new(source.path())
+                        .standard_filters(false)
+                        .build(),
+                ],
+            }],
+            &options,
+            Record::default(),
+        )
+        .unwrap();
+
+        // The content got carried over from the checkpoint, which is gone now that the snapshot
+        // references its pack
+        assert_eq!(stats.packs_written, 0);
+        assert_eq!(remote.list_keys(Typ::Pack).unwrap().count(), 1);
+        assert_eq!(remote.list_keys(Typ::Checkpoint).unwrap().count(), 0);
+
+        fetch(
+            &key,
+            &mut remote,
+            &mut &index[..],
+            &mut &map[..],
+            &mut &map[..],
+            target.path(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+        let restored = target.path().join(path.strip_prefix("/").unwrap());
+        assert_eq!(fs::read(restored).unwrap(), data);
+    }
+
+    #[test]
+    fn resume_missing_pack() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let (options, record) = (Options::default(), Record::default());
+
+        let (kept, gone) = (key.gen_id(), key.gen_id());
+        remote.write(Typ::Pack, kept, &b"kept"[..]).unwrap();
+
+        // Content split over both packs, and content that is all in the pack that is still there
+        let (split, whole) = (key.gen_id(), key.gen_id());
+        let map = Map::new().unwrap();
+        for (content, part, pack) in [(split, 0, kept), (split, 1, gone), (whole, 0, kept)] {
+            map.insert_chunk(content, part, pack, compress::Compression::Stored, 4)
+                .unwrap();
+        }
+        map.unload(
+            &key,
+            &options.compression,
+            remote
+                .write_multi_filename(Typ::Checkpoint, &checkpoint_name(&record))
+                .unwrap(),
+        )
+        .unwrap();
+
+        let checkpoint = resume(&key, &mut remote, &record, &options).unwrap();
+        assert!(checkpoint.resumed.find_packs(split).unwrap().is_empty());
+        assert_eq!(checkpoint.resumed.find_packs(whole).unwrap(), vec![kept]);
+    }
+
+    #[test]
+    fn multiple_sources() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let (first, second) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let target = tempfile::tempdir().unwrap();
+
+        fs::write(first.path().join("a"), b"a").unwrap();
+        fs::write(first.path().join("a.pyc"), b"pyc").unwrap();
+        fs::write(second.path().join("b"), b"b").unwrap();
+
+        let mut overrides = ignore::overrides::OverrideBuilder::new(first.path());
+        overrides.add("!*.pyc").unwrap();
+        let sources = vec![
+            Source {
+                name: "first".to_owned(),
+                walkers: vec![
+                    ignore::WalkBuilder::new(first.path())
+                        .overrides(overrides.build().unwrap())
+                        .build(),
+                ],
+            },
+            Source {
+                name: "second".to_owned(),
+                walkers: vec![ignore::WalkBuilder::new(second.path()).build()],
+            },
+        ];
+
+        let (mut index, mut map) = (vec![], vec![]);
+        append(
+            &key,
+            &mut remote,
+            &mut index,
+            &mut map,
+            sources,
+            &Options::default(),
+            Record::default(),
+        )
+        .unwrap();
+        fetch(
+            &key,
+            &mut remote,
+            &mut &index[..],
+            &mut &map[..],
+            &mut &map[..],
+            target.path(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+
+        let restored = |dir: &Path| target.path().join(dir.strip_prefix("/").unwrap());
+        assert_eq!(fs::read(restored(first.path()).join("a")).unwrap(), b"a");
+        assert!(!restored(first.path()).join("a.pyc").exists());
+        assert_eq!(fs::read(restored(second.path()).join("b")).unwrap(), b"b");
+    }
+
+    #[test]
+    fn error_policy() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let source = tempfile::tempdir().unwrap();
+
+        // Following the links walks into a loop which the walk reports as an error
+        fs::write(source.path().join("file"), b"Hello World!").unwrap();
+        symlink(source.path(), source.path().join("loop")).unwrap();
+
+        let backup = |remote: &mut SqlVFS, on_error| {
+            let (mut index, mut map) = (vec![], vec![]);
+            let stats = append(
+                &key,
+                remote,
+                &mut index,
+                &mut map,
+                vec![Source {
+                    name: "test".to_owned(),
+                    walkers: vec![
+                        ignore::WalkBuilder::new(source.path())
+                            .standard_filters(false)
+                            .follow_links(true)
+                            .build(),
+                    ],
+                }],
+                &Options {
+                    on_error,
+                    ..Options::default()
+                },
+                Record::default(),
+            );
+            stats.map(|stats| (stats, index))
+        };
+
+        let err = backup(&mut remote, ErrorPolicy::Abort).expect_err("loop aborts");
+        assert!(err.to_string().contains("loop"));
+
+        // The rest got stored and the record remembers what got left out and why
+        let (stats, index) = backup(&mut remote, ErrorPolicy::Skip).unwrap();
+        assert_eq!((stats.files_scanned, stats.files_skipped), (1, 1));
+        assert_eq!(stats.skipped.len(), 1);
+        assert_eq!(
+            stats.skipped[0].path,
+            format!("{}", source.path().join("loop").display())
+        );
+        assert!(!stats.skipped[0].reason.is_empty());
+
+        let record = record(&key, &mut &index[..]).unwrap().unwrap();
+        assert_eq!(record.stats.skipped, stats.skipped);
+    }
+
+    // Refuses to store any packs
+    struct NoPacks(SqlVFS);
+
+    impl Remote for NoPacks {
+        fn list_keys(&self, typ: Typ) -> Result<Box<dyn Iterator<Item = String>>, Box<dyn Error>> {
+            self.0.list_keys(typ)
+        }
+        fn write_filename<R: Read>(
+            &self,
+            typ: Typ,
+            filename: &str,
+            reader: R,
+        ) -> Result<(), Box<dyn Error>> {
+            self.0.write_filename(typ, filename, reader)
+        }
+        fn read_filename(
+            &mut self,
+            typ: Typ,
+            filename: &str,
+        ) -> Result<Box<dyn Read + Send>, Box<dyn Error>> {
+            self.0.read_filename(typ, filename)
+        }
+        fn delete_filename(&self, typ: Typ, filename: &str) -> Result<(), Box<dyn Error>> {
+            self.0.delete_filename(typ, filename)
+        }
+        fn stat_filename(
+            &self,
+            typ: Typ,
+            filename: &str,
+        ) -> Result<Option<crate::remote::Stat>, Box<dyn Error>> {
+            self.0.stat_filename(typ, filename)
+        }
+        fn write_multi_filename(
+            &self,
+            typ: Typ,
+            key: &str,
+        ) -> Result<Box<dyn Write + Send>, Box<dyn Error>> {
+            match typ {
+                Typ::Pack => Err("no packs".into()),
+                _ => self.0.write_multi_filename(typ, key),
+            }
+        }
+    }
+
+    #[test]
+    fn writer_error() {
+        let key = key::MemKey::new();
+        let mut remote = NoPacks(SqlVFS::new(None).unwrap());
+        let source = tempfile::tempdir().unwrap();
+        for i in 0..64 {
+            fs::write(source.path().join(format!("{i}")), format!("{i}")).unwrap();
+        }
+
+        // Far more files than fits in the queues, the walker must get hung up on instead of
+        // blocking on a full queue once the writer bails out
+        let err = append(
+            &key,
+            &mut remote,
+            &mut vec![],
+            &mut vec![],
+            vec![Source {
+                name: "test".to_owned(),
+                walkers: vec![ignore::WalkBuilder::new(source.path()).build()],
+            }],
+            &Options {
+                pipeline: Pipeline {
+                    hashers: 2,
+                    queue: 1,
+                },
+                ..Options::default()
+            },
+            Record::default(),
+        )
+        .expect_err("no packs");
+        assert_eq!(err.to_string(), "no packs");
+    }
+
+    #[test]
+    fn filtered() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        create_dir_all(source.path().join("keep/sub")).unwrap();
+        create_dir_all(source.path().join("skip")).unwrap();
+        fs::write(source.path().join("keep/a"), b"a").unwrap();
+        fs::write(source.path().join("keep/sub/b"), b"b").unwrap();
+        fs::write(source.path().join("keep/c.txt"), b"c").unwrap();
+        fs::write(source.path().join("skip/d"), b"d").unwrap();
+
+        let (mut index, mut map) = (vec![], vec![]);
+        append(
+            &key,
+            &mut remote,
+            &mut index,
+            &mut map,
+            vec![Source {
+                name: "test".to_owned(),
+                walkers: vec![ignore::WalkBuilder::new(source.path()).build()],
+            }],
+            &Options::default(),
+            Record::default(),
+        )
+        .unwrap();
+
+        let options = RestoreOptions {
+            filter: Filter {
+                include: vec![format!("{}/keep", source.path().display())],
+                exclude: vec!["*.txt".to_owned()],
+            },
+            ..RestoreOptions::default()
+        };
+        fetch(
+            &key,
+            &mut remote,
+            &mut &index[..],
+            &mut &map[..],
+            &mut &map[..],
+            target.path(),
+            &options,
+        )
+        .unwrap();
+
+        let restored = target.path().join(source.path().strip_prefix("/").unwrap());
+        assert_eq!(fs::read(restored.join("keep/a")).unwrap(), b"a");
+        assert_eq!(fs::read(restored.join("keep/sub/b")).unwrap(), b"b");
+        assert!(!restored.join("keep/c.txt").exists());
+        assert!(!restored.join("skip").exists());
+    }
+
+    #[test]
+    fn metadata() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        let (dir, file) = (source.path().join("dir"), source.path().join("dir/file"));
+        create_dir_all(&dir).unwrap();
+        fs::write(&file, b"Hello World!").unwrap();
+
+        let (atime, mtime) = (TimeSpec::new(1_000_000, 7), TimeSpec::new(2_000_000, 42));
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o750)).unwrap();
+        for path in [&file, &dir] {
+            utimensat(
+                AT_FDCWD,
+                path,
+                &atime,
+                &mtime,
+                UtimensatFlags::NoFollowSymlink,
+            )
+            .unwrap();
+        }
+
+        roundtrip(source.path(), target.path(), &Options::default());
+
+        let restored = target.path().join(source.path().strip_prefix("/").unwrap());
+        for (path, mode) in [
+            (restored.join("dir/file"), 0o640),
+            (restored.join("dir"), 0o750),
+        ] {
+            let meta = fs::metadata(path).unwrap();
+            assert_eq!(meta.mode() & 0o7777, mode);
+            assert_eq!((meta.mtime(), meta.mtime_nsec()), (2_000_000, 42));
+            assert_eq!((meta.atime(), meta.atime_nsec()), (1_000_000, 7));
+        }
+    }
+
+    #[test]
+    fn mismatch() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let policy = compress::Policy::default();
+
+        let index = Index::new().unwrap();
+        let map = Map::new().unwrap();
+
+        // Content stored under the hash of some other content
+        let (good, bad) = (b"Hello World!", b"Hello Corrupted!");
+        let good_hash = hash::hash(&key, &mut &good[..]).unwrap();
+        let bad_hash = hash::hash(&key, &mut &b"Hello Original!"[..]).unwrap();
+
+        let pack_id = key.gen_id();
+        let mut pack = PackBuilder::new(
+            pack_id,
+            remote.write_multi(Typ::Pack, pack_id).unwrap(),
+            PackConfig::default().target_size,
+        )
+        .unwrap();
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let meta = meta::Metadata::from_fs(
+            &file.as_file().metadata().unwrap(),
+            &mut meta::Names::default(),
+        );
+        for (path, data, content) in [("good", &good[..], good_hash), ("bad", &bad[..], bad_hash)] {
+            let mut enc = vec![];
+            crypto::encrypt(&key, data)
+                .unwrap()
+                .read_to_end(&mut enc)
+                .unwrap();
+            pack.append(content, &mut &enc[..]).unwrap();
+            map.insert_chunk(content, 0, pack_id, compress::Compression::Stored, 0)
+                .unwrap();
+            index
+                .insert_file(Path::new(path), &meta, 0, content, None)
+                .unwrap();
+        }
+        pack.finalize(&key).unwrap();
+        index.insert_source(0, "test").unwrap();
+
+        let (mut index_content, mut map_content) = (vec![], vec![]);
+        index
+            .unload(&key, &policy, &[], &mut index_content)
+            .unwrap();
+        map.unload(&key, &policy, &mut map_content).unwrap();
+
+        let mut restore = |options: &RestoreOptions| {
+            fetch(
+                &key,
+                &mut remote,
+                &mut &index_content[..],
+                &mut &map_content[..],
+                &mut &map_content[..],
+                target.path(),
+                options,
+            )
+        };
+
+        // The bad file is never put into place
+        assert!(restore(&RestoreOptions::default()).is_err());
+        assert!(!target.path().join("bad").exists());
+
+        let options = RestoreOptions {
+            keep_going: true,
+            ..RestoreOptions::default()
+        };
+        let report = restore(&options).unwrap();
+        assert_eq!(report.restored, 1);
+        assert_eq!(report.mismatched, vec!["bad".to_owned()]);
+        assert_eq!(fs::read(target.path().join("good")).unwrap(), good);
+        assert!(!target.path().join("bad").exists());
+        assert_eq!(fs::read_dir(target.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn diff_snapshots() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let source = tempfile::tempdir().unwrap();
+
+        let backup = |remote: &mut SqlVFS| {
+            let mut index = vec![];
+            append(
+                &key,
+                remote,
+                &mut index,
+                &mut vec![],
+                vec![Source {
+                    name: "test".to_owned(),
+                    walkers: vec![ignore::WalkBuilder::new(source.path()).build()],
+                }],
+                &Options::default(),
+                Record::default(),
+            )
+            .unwrap();
+            index
+        };
+
+        for name in ["a", "b", "c", "e"] {
+            fs::write(source.path().join(name), name).unwrap();
+        }
+        create_dir_all(source.path().join("f")).unwrap();
+        symlink("a", source.path().join("g")).unwrap();
+        let old = backup(&mut remote);
+
+        fs::write(source.path().join("b"), b"Hello World!").unwrap();
+        fs::remove_file(source.path().join("c")).unwrap();
+        fs::write(source.path().join("d"), b"d").unwrap();
+        fs::set_permissions(source.path().join("e"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::remove_dir(source.path().join("f")).unwrap();
+        symlink("a", source.path().join("f")).unwrap();
+        fs::remove_file(source.path().join("g")).unwrap();
+        symlink("b", source.path().join("g")).unwrap();
+        let new = backup(&mut remote);
+
+        let diff = diff(&key, &mut &old[..], &mut &new[..]).unwrap();
+
+        // The root directory's mtime changes too since entries were added + removed
+        let path = |name| format!("{}", source.path().join(name).display());
+        let root = format!("{}", source.path().display());
+        let changes: Vec<_> = diff
+            .changes
+            .into_iter()
+            .filter(|(_, p)| *p != root)
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (Change::Modified, path("b")),
+                (Change::Removed, path("c")),
+                (Change::Added, path("d")),
+                (Change::Metadata, path("e")),
+                (Change::Modified, path("f")),
+                (Change::Modified, path("g")),
+            ]
+        );
+        assert_eq!(diff.added_bytes, 1);
+        assert_eq!(diff.removed_bytes, 1);
+        // Symlinks are as large as their target
+        assert_eq!(diff.modified_bytes, 12 + 1 + 1);
+    }
+
+    #[test]
+    fn diff_xattrs_and_links() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let source = tempfile::tempdir().unwrap();
+
+        // Not every filesystem the tests run on has user xattrs
+        let (a, b) = (source.path().join("a"), source.path().join("b"));
+        fs::write(&a, b"a").unwrap();
+        fs::write(&b, b"b").unwrap();
+        if xattr::set(&a, "user.rozen", b"old").is_err() {
+            return;
+        }
+
+        let options = Options {
+            xattrs: true,
+            ..Options::default()
+        };
+        let backup = |remote: &mut SqlVFS| {
+            let mut index = vec![];
+            let walkers = vec![ignore::WalkBuilder::new(source.path()).build()];
+            let sources = vec![Source {
+                name: "test".to_owned(),
+                walkers,
+            }];
+            append(
+                &key,
+                remote,
+                &mut index,
+                &mut vec![],
+                sources,
+                &options,
+                Record::default(),
+            )
+            .unwrap();
+            index
+        };
+        let old = backup(&mut remote);
+
+        // Only the xattr value of a changes, b gains a hardlink
+        xattr::set(&a, "user.rozen", b"new").unwrap();
+        fs::hard_link(&b, source.path().join("c")).unwrap();
+        let new = backup(&mut remote);
+
+        let diff = diff(&key, &mut &old[..], &mut &new[..]).unwrap();
+        let path = |name| format!("{}", source.path().join(name).display());
+        let root = format!("{}", source.path().display());
+        let changes: Vec<_> = diff
+            .changes
+            .into_iter()
+            .filter(|(_, p)| *p != root)
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (Change::Metadata, path("a")),
+                (Change::Metadata, path("b")),
+                (Change::Added, path("c")),
+            ]
+        );
+    }
+
+    #[test]
+    fn export() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let source = tempfile::tempdir().unwrap();
+
+        // Past the 100 bytes of the ustar name field
+        let long = source.path().join("d".repeat(120));
+        create_dir_all(&long).unwrap();
+        fs::write(long.join("a"), b"Hello World!").unwrap();
+        fs::hard_link(long.join("a"), source.path().join("b")).unwrap();
+        symlink("b", source.path().join("link")).unwrap();
+
+        let time = TimeSpec::new(1_000_000_000, 123_456_789);
+        let flags = UtimensatFlags::NoFollowSymlink;
+        utimensat(AT_FDCWD, &source.path().join("b"), &time, &time, flags).unwrap();
+
+        let (mut index, mut map) = (vec![], vec![]);
+        append(
+            &key,
+            &mut remote,
+            &mut index,
+            &mut map,
+            vec![Source {
+                name: "test".to_owned(),
+                walkers: vec![
+                    ignore::WalkBuilder::new(source.path())
+                        .standard_filters(false)
+                        .build(),
+                ],
+            }],
+            &Options::default(),
+            Record::default(),
+        )
+        .unwrap();
+
+        let tar = export_tar(
+            &key,
+            &mut remote,
+            &mut &index[..],
+            &mut &map[..],
+            &mut &map[..],
+            vec![],
+        )
+        .unwrap();
+
+        let mut entries = HashMap::new();
+        for entry in tar::Archive::new(&tar[..]).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let typ = entry.header().entry_type();
+            let link = entry.link_name().unwrap().map(Cow::into_owned);
+            let mtime = entry.pax_extensions().unwrap().and_then(|mut pax| {
+                pax.find_map(|ext| {
+                    let ext = ext.unwrap();
+                    (ext.key().unwrap() == "mtime").then(|| ext.value().unwrap().to_owned())
+                })
+            });
+            let path = PathBuf::from("/").join(entry.path().unwrap());
+
+            let mut data = vec![];
+            entry.read_to_end(&mut data).unwrap();
+            entries.insert(path, (typ, link, mtime, data));
+        }
+
+        // Whichever of the hardlinks comes first holds the content
+        let (a, b) = (
+            &entries[&long.join("a")],
+            &entries[&source.path().join("b")],
+        );
+        let (content, hardlink) = if a.0.is_file() { (a, b) } else { (b, a) };
+        assert_eq!(content.3, b"Hello World!");
+        assert!(hardlink.0.is_hard_link());
+        let target = Path::new("/").join(hardlink.1.as_ref().unwrap());
+        assert!([long.join("a"), source.path().join("b")].contains(&target));
+        assert_eq!(b.2.as_deref(), Some("1000000000.123456789"));
+
+        let link = &entries[&source.path().join("link")];
+        assert!(link.0.is_symlink());
+        assert_eq!(link.1.as_deref(), Some(Path::new("b")));
+        assert!(entries[&long].0.is_dir());
+    }
+
+    #[test]
+    fn stdin_stream() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        let (mut index, mut map) = (vec![], vec![]);
+        let stream = Stream::File {
+            name: "db.sql".to_owned(),
+            reader: &b"CREATE TABLE dump;"[..],
+        };
+        let stats = append_stream(
+            &key,
+            &mut remote,
+            &mut index,
+            &mut map,
+            stream,
+            &Options::default(),
+            Record::default(),
+        )
+        .unwrap();
+        assert_eq!((stats.files_new, stats.bytes_read), (1, 18));
+
+        fetch(
+            &key,
+            &mut remote,
+            &mut &index[..],
+            &mut &map[..],
+            &mut &map[..],
+            target.path(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+
+        let restored = target.path().join("db.sql");
+        assert_eq!(fs::read(&restored).unwrap(), b"CREATE TABLE dump;");
+        assert_eq!(fs::metadata(&restored).unwrap().mode() & 0o7777, 0o600);
+    }
+
+    #[test]
+    fn tar_import() {
+        let key = key::MemKey::new();
+        let mut remote = SqlVFS::new(None).unwrap();
+        let target = tempfile::tempdir().unwrap();
+
+        let mut builder = tar::Builder::new(vec![]);
+        let mut add = |path: &str, typ, link: Option<&str>, data: &[u8]| {
+            let mut header = tar::Header::new_ustar();
+            header.set_entry_type(typ);
+            // Raw so that the ".." gets through
+            truncate_into(&mut header.as_old_mut().name, path.as_bytes());
+            if let Some(link) = link {
+                header.set_link_name(link).unwrap();
+            }
+            header.set_mode(0o640);
+            header.set_uid(u64::from(uzers::get_current_uid()));
+            header.set_gid(u64::from(uzers::get_current_gid()));
+            header.set_mtime(1_000_000_000);
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        };
+        add("global", tar::EntryType::XGlobalHeader, None, b"");
+        add("./dir", tar::EntryType::Directory, None, b"");
+        add("./dir/a", tar::EntryType::Regular, None, b"Hello World!");
+        add("./dir/b", tar::EntryType::Link, Some("./dir/a"), b"");
+        add("./link", tar::EntryType::Symlink, Some("dir/a"), b"");
+        add("./../escape", tar::EntryType::Regular, None, b"Nope");
+        add("./up", tar::EntryType::Symlink, Some(".."), b"");
+        add("./up/escape", tar::EntryType::Regular, None, b"Nope");
+        let tar = builder.into_inner().unwrap();
+
+        let (mut index, mut map) = (vec![], vec![]);
+        let stats = append_stream(
+            &key,
+            &mut remote,
+            &mut index,
+            &mut map,
+            Stream::Tar(&tar[..]),
+            &Options::default(),
+            Record::default(),
+        )
+        .unwrap();
+        assert_eq!((stats.files_scanned, stats.files_skipped), (2, 2));
+        assert_eq!((stats.dedup_hits, stats.bytes_read), (1, 12));
+        let skipped: Vec<_> = stats.skipped.iter().map(|s| s.path.as_str()).collect();
+        assert_eq!(skipped, vec!["./../escape", "up/escape"]);
+
+        fetch(
+            &key,
+            &mut remote,
+            &mut &index[..],
+            &mut &map[..],
+            &mut &map[..],
+            target.path(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+
+        let restored = |name| target.path().join(name);
+        assert_eq!(fs::read(restored("dir/b")).unwrap(), b"Hello World!");
+        assert_eq!(
+            fs::metadata(restored("dir/a")).unwrap().ino(),
+            fs::metadata(restored("dir/b")).unwrap().ino()
+        );
+        assert_eq!(fs::read_link(restored("link")).unwrap(), Path::new("dir/a"));
+
+        let meta = fs::metadata(restored("dir/a")).unwrap();
+        assert_eq!((meta.mode() & 0o7777, meta.mtime()), (0o640, 1_000_000_000));
+        assert!(!target.path().parent().unwrap().join("escape").exists());
+    }
+
+    #[test]
+    fn pax_times() {
+        for time in [(1, 5), (0, 0), (-2, 500_000_000), (-1, 0)] {
+            assert_eq!(parse_pax_time(&pax_time(time)).unwrap(), time);
+        }
+        assert_eq!(parse_pax_time("1.5").unwrap(), (1, 500_000_000));
+        assert_eq!(parse_pax_time("-1.5").unwrap(), (-2, 500_000_000));
+    }
+}
@@ -1,6 +1,9 @@
+use log::info;
 use rusqlite as rs;
 
+use std::collections::HashSet;
 use std::error::Error;
+use std::io;
 use std::io::{Read, Seek as _, SeekFrom, Write, copy};
 use std::path::Path;
 use zstd::stream::read::Decoder;
@@ -8,15 +11,22 @@ use zstd::stream::read::Encoder;
 
 use rusqlite::Connection;
 
+use crate::rcore::compress;
 use crate::rcore::crypto;
 use crate::rcore::hash;
 use crate::rcore::key;
+use crate::rcore::meta;
+
+use crate::ingest::Parent;
 
 use crate::rarc::ltvc::indexing::LtvcIndexing;
 use crate::rarc::ltvc::linear::EdatStream;
 use crate::rarc::ltvc::linear::Header;
 use crate::rarc::ltvc::linear::LtvcLinear;
 
+use crate::snapshot::Change;
+use crate::snapshot::Filter;
+
 // TODO:
 // - Figure out a better implementation, the sqlite is a shared resource, but we want
 //   to have multiple databases for various parts and these parts will want to own their
@@ -67,10 +77,19 @@ impl SqlDb {
         Ok(())
     }
 
+    // Point in time copy, for unloading a db that is still being written to
+    fn copy(&self) -> Result<Self, Box<dyn Error>> {
+        let db_tmp = tempfile::NamedTempFile::new()?;
+        self.conn
+            .execute("VACUUM INTO ?", rs::params![db_tmp.path().display().to_string()])?;
+        let conn = Connection::open(db_tmp.path())?;
+        Ok(Self { db_tmp, conn })
+    }
+
     fn load<R: Read>(reader: &mut R, key: &key::MemKey) -> Result<Self, Box<dyn Error>> {
         let ltvc = LtvcLinear::new(reader);
 
-        for EdatStream { header, data } in ltvc {
+        for EdatStream { header, mut data } in ltvc {
             match header {
                 Header::Shdr | Header::Pidx => {
                     let mut db_tmp = tempfile::NamedTempFile::new()?;
@@ -83,6 +102,11 @@ impl SqlDb {
                     return Ok(Self { db_tmp, conn });
                 }
 
+                // The record is only read by `Index::load_record`, drain it to get to the db
+                Header::Smta => {
+                    copy(&mut data, &mut io::sink())?;
+                }
+
                 // Skip header we don't care for
                 _ => (),
             }
@@ -96,18 +120,27 @@ impl SqlDb {
         self,
         header: UnloadType,
         key: &key::MemKey,
+        level: i32,
+        record: Option<&[u8]>,
         writer: W,
     ) -> Result<(), Box<dyn Error>> {
         let _ = self.conn.close();
 
         let mut ltvc = LtvcIndexing::new(writer)?;
 
+        if let Some(record) = record {
+            let record_hash = hash::hash(key, &mut &record[..])?;
+            let comp = Encoder::new(record, level)?;
+            let mut enc = crypto::encrypt(key, comp)?;
+            ltvc.append_snapshot_meta(record_hash, &mut enc)?;
+        }
+
         let mut db_file = self.db_tmp.into_file();
 
         let content_hash = hash::hash(key, &mut db_file)?;
         db_file.seek(SeekFrom::Start(0))?;
 
-        let comp = Encoder::new(&mut db_file, 21)?;
+        let comp = Encoder::new(&mut db_file, level)?;
         let mut enc = crypto::encrypt(key, comp)?;
 
         match header {
@@ -131,9 +164,43 @@ impl Index {
         db.conn.execute_batch(
             "CREATE TABLE files (
                     path VARCHAR NOT NULL,
+                    file_type INTEGER NOT NULL,
                     permission INTEGER NOT NULL,
-                    content_hash VARCHAR NOT NULL
-                 );",
+                    size INTEGER NOT NULL,
+                    mtime INTEGER NOT NULL,
+                    mtime_nsec INTEGER NOT NULL,
+                    atime INTEGER NOT NULL,
+                    atime_nsec INTEGER NOT NULL,
+                    ctime INTEGER NOT NULL,
+                    ctime_nsec INTEGER NOT NULL,
+                    uid INTEGER NOT NULL,
+                    gid INTEGER NOT NULL,
+                    user VARCHAR,
+                    grp VARCHAR,
+                    inode INTEGER NOT NULL,
+                    device INTEGER NOT NULL,
+                    rdev INTEGER NOT NULL,
+                    link_target VARCHAR,
+                    link_group INTEGER,
+                    content_hash VARCHAR,
+                    source INTEGER NOT NULL
+                 );
+                 CREATE TABLE sources (
+                    id INTEGER NOT NULL,
+                    name VARCHAR NOT NULL
+                 );
+                 CREATE TABLE xattrs (
+                    file_id INTEGER NOT NULL,
+                    name BLOB NOT NULL,
+                    value BLOB NOT NULL
+                 );
+                 CREATE TABLE extents (
+                    file_id INTEGER NOT NULL,
+                    offset INTEGER NOT NULL,
+                    length INTEGER NOT NULL
+                 );
+                 CREATE INDEX xattrs_file ON xattrs (file_id);
+                 CREATE INDEX extents_file ON extents (file_id);",
         )?;
 
         Ok(Self { db })
@@ -145,31 +212,235 @@ impl Index {
         })
     }
 
+    // The record goes in ahead of the db, see `load_record`
     pub(crate) fn unload<W: Write>(
         self,
         key: &key::MemKey,
+        policy: &compress::Policy,
+        record: &[u8],
         writer: W,
     ) -> Result<(), Box<dyn Error>> {
-        self.db.unload(UnloadType::Shdr, key, writer)?;
+        self.db
+            .unload(UnloadType::Shdr, key, policy.level, Some(record), writer)?;
         Ok(())
     }
 
-    // TODO: improve the types
-    pub(crate) fn insert_file(&self, path: &Path, hash: hash::Hash) -> Result<(), Box<dyn Error>> {
+    // Only reads up to the end of the record, the db that follows is never touched, None for an
+    // index without a record
+    pub(crate) fn load_record<R: Read>(
+        index: &mut R,
+        key: &key::MemKey,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let Some(EdatStream { header, data }) = LtvcLinear::new(index).next() else {
+            return Err("Did not find any stream in the index!".into());
+        };
+        let Header::Smta = header else {
+            return Ok(None);
+        };
+
+        let mut dec = crypto::decrypt(key, data)?;
+        let mut record = vec![];
+        Decoder::new(&mut dec)?.read_to_end(&mut record)?;
+        Ok(Some(record))
+    }
+
+    // Files that are hardlinked to each other shares the same link group, returns the id of the
+    // entry for the side tables
+    pub(crate) fn insert_file(
+        &self,
+        path: &Path,
+        meta: &meta::Metadata,
+        source: usize,
+        hash: hash::Hash,
+        link_group: Option<u64>,
+    ) -> Result<i64, Box<dyn Error>> {
+        self.insert(path, meta, source, None, link_group, Some(hash))
+    }
+
+    // Directories, symlinks and special files, these have no content
+    pub(crate) fn insert_special(
+        &self,
+        path: &Path,
+        meta: &meta::Metadata,
+        source: usize,
+        target: Option<&Path>,
+    ) -> Result<i64, Box<dyn Error>> {
+        self.insert(path, meta, source, target, None, None)
+    }
+
+    fn insert(
+        &self,
+        path: &Path,
+        meta: &meta::Metadata,
+        source: usize,
+        target: Option<&Path>,
+        link_group: Option<u64>,
+        hash: Option<hash::Hash>,
+    ) -> Result<i64, Box<dyn Error>> {
         let mut file_stmt = self.db.conn.prepare_cached(
             "INSERT INTO files
-                 (path, permission, content_hash)
+                 (path, file_type, permission, size, mtime, mtime_nsec, atime, atime_nsec,
+                  ctime, ctime_nsec, uid, gid, user, grp, inode, device, rdev, link_target,
+                  link_group, content_hash, source)
                  VALUES
-                 (?, ?, ?)",
+                 (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
         )?;
 
+        // Sqlite only has signed 64bit ints, inode + devices are stored bit for bit
         file_stmt.execute(rs::params![
             format!("{}", path.display()),
-            0000,
-            hash::to_hex(hash),
+            meta.file_type.to_flag(),
+            meta.mode,
+            i64::try_from(meta.size)?,
+            meta.mtime,
+            meta.mtime_nsec,
+            meta.atime,
+            meta.atime_nsec,
+            meta.ctime,
+            meta.ctime_nsec,
+            meta.uid,
+            meta.gid,
+            meta.user,
+            meta.group,
+            i64::from_ne_bytes(meta.inode.to_ne_bytes()),
+            i64::from_ne_bytes(meta.device.to_ne_bytes()),
+            i64::from_ne_bytes(meta.rdev.to_ne_bytes()),
+            target.map(|t| format!("{}", t.display())),
+            link_group.map(i64::try_from).transpose()?,
+            hash.map(hash::to_hex),
+            i64::try_from(source)?,
         ])?;
+        Ok(self.db.conn.last_insert_rowid())
+    }
+
+    // For when an entry only turns out to have hardlinks once they show up after it
+    pub(crate) fn set_link_group(
+        &self,
+        file_id: i64,
+        link_group: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut group_stmt = self.db.conn.prepare_cached(
+            "UPDATE files
+                 SET link_group = ?
+                 WHERE rowid = ?",
+        )?;
+
+        group_stmt.execute(rs::params![i64::try_from(link_group)?, file_id])?;
+        Ok(())
+    }
+
+    pub(crate) fn insert_source(&self, id: usize, name: &str) -> Result<(), Box<dyn Error>> {
+        let mut source_stmt = self.db.conn.prepare_cached(
+            "INSERT INTO sources
+                 (id, name)
+                 VALUES
+                 (?, ?)",
+        )?;
+
+        source_stmt.execute(rs::params![i64::try_from(id)?, name])?;
+        Ok(())
+    }
+
+    pub(crate) fn insert_xattrs(
+        &self,
+        file_id: i64,
+        xattrs: &[meta::Xattr],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut xattr_stmt = self.db.conn.prepare_cached(
+            "INSERT INTO xattrs
+                 (file_id, name, value)
+                 VALUES
+                 (?, ?, ?)",
+        )?;
+
+        for (name, value) in xattrs {
+            xattr_stmt.execute(rs::params![file_id, name, value])?;
+        }
+        Ok(())
+    }
+
+    // Only sparse files gets their extents recorded, without any the content starts at 0 and
+    // whatever is past it up to the size is a hole
+    pub(crate) fn insert_extents(
+        &self,
+        file_id: i64,
+        extents: &[meta::Extent],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut extent_stmt = self.db.conn.prepare_cached(
+            "INSERT INTO extents
+                 (file_id, offset, length)
+                 VALUES
+                 (?, ?, ?)",
+        )?;
+
+        for (offset, length) in extents {
+            extent_stmt.execute(rs::params![
+                file_id,
+                i64::try_from(*offset)?,
+                i64::try_from(*length)?
+            ])?;
+        }
         Ok(())
     }
+
+    // Fingerprint + content + extents of every regular file, keyed by path
+    pub(crate) fn fingerprints(&self) -> Result<Parent, Box<dyn Error>> {
+        let mut query_stmt = self.db.conn.prepare_cached(
+            "SELECT path, size, mtime, mtime_nsec, ctime, ctime_nsec, inode, content_hash
+             FROM files
+             WHERE file_type = ?
+             AND content_hash IS NOT NULL",
+        )?;
+
+        let mut rows = query_stmt.query(rs::params![meta::FileType::File.to_flag()])?;
+        let mut ret = Parent::new();
+        while let Some(row) = rows.next()? {
+            let (size, inode, hash): (i64, i64, String) = (row.get(1)?, row.get(6)?, row.get(7)?);
+            let fingerprint = meta::Fingerprint {
+                size: u64::try_from(size)?,
+                mtime: row.get(2)?,
+                mtime_nsec: row.get(3)?,
+                ctime: row.get(4)?,
+                ctime_nsec: row.get(5)?,
+                inode: u64::from_ne_bytes(inode.to_ne_bytes()),
+            };
+            ret.insert(row.get(0)?, (fingerprint, hash::from_hex(&hash)?, vec![]));
+        }
+
+        let mut extent_stmt = self.db.conn.prepare_cached(
+            "SELECT f.path, e.offset, e.length
+             FROM extents e
+             JOIN files f ON f.rowid = e.file_id
+             ORDER BY e.rowid ASC",
+        )?;
+        let mut rows = extent_stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let (path, offset, length): (String, i64, i64) =
+                (row.get(0)?, row.get(1)?, row.get(2)?);
+            if let Some((_, _, extents)) = ret.get_mut(&path) {
+                extents.push((u64::try_from(offset)?, u64::try_from(length)?));
+            }
+        }
+        Ok(ret)
+    }
+
+    // All of the content referenced by this index
+    pub(crate) fn content_hashes(&self) -> Result<HashSet<hash::Hash>, Box<dyn Error>> {
+        let mut query_stmt = self.db.conn.prepare_cached(
+            "SELECT DISTINCT content_hash
+             FROM files
+             WHERE content_hash IS NOT NULL",
+        )?;
+
+        let hashes = query_stmt
+            .query_map([], |row| {
+                let hash: String = row.get(0)?;
+                Ok(hash::from_hex(&hash)
+                    .map_err(|e| rs::types::FromSqlError::Other(Box::new(e)))?)
+            })?
+            .collect::<Result<HashSet<_>, _>>()?;
+        Ok(hashes)
+    }
 }
 
 pub(crate) struct Map {
@@ -183,7 +454,16 @@ impl Map {
         db.conn.execute_batch(
             "CREATE TABLE packfiles (
                     content_hash VARCHAR NOT NULL,
-                    pack_hash VARCHAR NOT NULL
+                    part INTEGER NOT NULL,
+                    pack_hash VARCHAR NOT NULL,
+                    compression INTEGER NOT NULL,
+                    length INTEGER NOT NULL,
+                    UNIQUE(content_hash, part)
+                );
+                 CREATE TABLE packs (
+                    pack_hash VARCHAR NOT NULL,
+                    length INTEGER NOT NULL,
+                    UNIQUE(pack_hash)
                 );",
         )?;
 
@@ -199,88 +479,411 @@ impl Map {
     pub(crate) fn unload<W: Write>(
         self,
         key: &key::MemKey,
+        policy: &compress::Policy,
         writer: W,
     ) -> Result<(), Box<dyn Error>> {
-        self.db.unload(UnloadType::Pidx, key, writer)?;
+        self.db
+            .unload(UnloadType::Pidx, key, policy.level, None, writer)?;
         Ok(())
     }
 
     // TODO: improve the types
+    // The length is of the record in the pack, so how much of a pack is live is known without
+    // reading the pack. Content that is already in the map stays where it is
     pub(crate) fn insert_chunk(
         &self,
         chunk: hash::Hash,
+        part: u32,
         pack: hash::Hash,
+        comp: compress::Compression,
+        length: u64,
     ) -> Result<(), Box<dyn Error>> {
         let mut pack_stmt = self.db.conn.prepare_cached(
-            "INSERT INTO packfiles
-                 (content_hash, pack_hash)
+            "INSERT OR IGNORE INTO packfiles
+                 (content_hash, part, pack_hash, compression, length)
+                 VALUES
+                 (?, ?, ?, ?, ?)",
+        )?;
+
+        pack_stmt.execute(rs::params![
+            hash::to_hex(chunk),
+            part,
+            hash::to_hex(pack),
+            comp.to_flag(),
+            i64::try_from(length)?,
+        ])?;
+        Ok(())
+    }
+
+    // Length of all the records in the pack, live or not, the first one recorded sticks
+    pub(crate) fn insert_pack(&self, pack: hash::Hash, length: u64) -> Result<(), Box<dyn Error>> {
+        let mut pack_stmt = self.db.conn.prepare_cached(
+            "INSERT OR IGNORE INTO packs
+                 (pack_hash, length)
                  VALUES
                  (?, ?)",
         )?;
 
-        pack_stmt.execute(rs::params![hash::to_hex(chunk), hash::to_hex(pack),])?;
+        pack_stmt.execute(rs::params![hash::to_hex(pack), i64::try_from(length)?])?;
         Ok(())
     }
 
-    pub(crate) fn find_pack(&self, chunk: hash::Hash) -> Result<hash::Hash, Box<dyn Error>> {
+    // Length of all the records in the pack, none if the map doesn't know the pack
+    pub(crate) fn find_pack(&self, pack: hash::Hash) -> Result<Option<u64>, Box<dyn Error>> {
+        let mut query_stmt = self.db.conn.prepare_cached(
+            "SELECT length
+             FROM packs
+             WHERE pack_hash = ?",
+        )?;
+
+        let mut rows = query_stmt.query(rs::params![hash::to_hex(pack)])?;
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        let length: i64 = row.get(0)?;
+        Ok(Some(u64::try_from(length)?))
+    }
+
+    pub(crate) fn find_compression(
+        &self,
+        chunk: hash::Hash,
+    ) -> Result<compress::Compression, Box<dyn Error>> {
+        let mut query_stmt = self.db.conn.prepare_cached(
+            "SELECT compression
+             FROM packfiles
+             WHERE content_hash = ?
+             AND part = 0",
+        )?;
+
+        let flag: u8 = query_stmt.query_row(rs::params![hash::to_hex(chunk)], |row| row.get(0))?;
+        Ok(compress::Compression::from_flag(flag)?)
+    }
+
+    // Returns the packs holding each part of the chunk in part order, empty if unknown
+    pub(crate) fn find_packs(&self, chunk: hash::Hash) -> Result<Vec<hash::Hash>, Box<dyn Error>> {
         let mut query_stmt = self.db.conn.prepare_cached(
             "SELECT pack_hash
              FROM packfiles
-             WHERE content_hash = ?",
+             WHERE content_hash = ?
+             ORDER BY part ASC",
         )?;
 
-        Ok(
-            query_stmt.query_row(rs::params![hash::to_hex(chunk)], |row| {
+        let packs = query_stmt
+            .query_map(rs::params![hash::to_hex(chunk)], |row| {
                 let hash: String = row.get(0)?;
                 Ok(hash::from_hex(&hash)
                     .map_err(|e| rs::types::FromSqlError::Other(Box::new(e)))?)
-            })?,
-        )
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(packs)
+    }
+
+    // Returns the pack + record length of each part of the chunk in part order, empty if unknown
+    pub(crate) fn find_parts(
+        &self,
+        chunk: hash::Hash,
+    ) -> Result<Vec<(hash::Hash, u64)>, Box<dyn Error>> {
+        let mut query_stmt = self.db.conn.prepare_cached(
+            "SELECT pack_hash, length
+             FROM packfiles
+             WHERE content_hash = ?
+             ORDER BY part ASC",
+        )?;
+
+        let parts = query_stmt
+            .query_map(rs::params![hash::to_hex(chunk)], |row| {
+                let hash: String = row.get(0)?;
+                Ok((
+                    hash::from_hex(&hash)
+                        .map_err(|e| rs::types::FromSqlError::Other(Box::new(e)))?,
+                    u64::try_from(row.get::<_, i64>(1)?)
+                        .map_err(|e| rs::types::FromSqlError::Other(Box::new(e)))?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(parts)
+    }
+
+    // Every (content, part, pack, record length) in the map
+    #[expect(clippy::type_complexity)]
+    pub(crate) fn chunks(&self) -> Result<Vec<(hash::Hash, u32, hash::Hash, u64)>, Box<dyn Error>> {
+        let mut query_stmt = self.db.conn.prepare_cached(
+            "SELECT content_hash, part, pack_hash, length
+             FROM packfiles",
+        )?;
+
+        let chunks = query_stmt
+            .query_map([], |row| {
+                let chunk: String = row.get(0)?;
+                let pack: String = row.get(2)?;
+                Ok((
+                    hash::from_hex(&chunk)
+                        .map_err(|e| rs::types::FromSqlError::Other(Box::new(e)))?,
+                    row.get(1)?,
+                    hash::from_hex(&pack)
+                        .map_err(|e| rs::types::FromSqlError::Other(Box::new(e)))?,
+                    u64::try_from(row.get::<_, i64>(3)?)
+                        .map_err(|e| rs::types::FromSqlError::Other(Box::new(e)))?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(chunks)
     }
+
+    // Repoint a chunk that was in the old pack to the new pack, returns the rows changed
+    pub(crate) fn move_chunk(
+        &self,
+        chunk: hash::Hash,
+        old: hash::Hash,
+        new: hash::Hash,
+    ) -> Result<usize, Box<dyn Error>> {
+        let mut move_stmt = self.db.conn.prepare_cached(
+            "UPDATE packfiles
+             SET pack_hash = ?
+             WHERE content_hash = ?
+             AND pack_hash = ?",
+        )?;
+
+        Ok(move_stmt.execute(rs::params![
+            hash::to_hex(new),
+            hash::to_hex(chunk),
+            hash::to_hex(old),
+        ])?)
+    }
+
+    pub(crate) fn copy(&self) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            db: self.db.copy()?,
+        })
+    }
+
+    // Pulls in every chunk of the other map that this one doesn't have yet
+    pub(crate) fn merge(&self, other: &Self) -> Result<(), Box<dyn Error>> {
+        self.db.attach(other.db.db_tmp.path(), "other")?;
+        self.db.conn.execute_batch(
+            "INSERT OR IGNORE INTO main.packfiles
+             SELECT content_hash, part, pack_hash, compression, length
+             FROM other.packfiles;
+             INSERT OR IGNORE INTO main.packs
+             SELECT pack_hash, length
+             FROM other.packs;",
+        )?;
+        self.db.detach("other")?;
+        Ok(())
+    }
+
+    // Drop every part of any chunk with a part in the pack, returns the rows removed
+    pub(crate) fn remove_contents(&self, pack: hash::Hash) -> Result<usize, Box<dyn Error>> {
+        let mut remove_stmt = self.db.conn.prepare_cached(
+            "DELETE FROM packfiles
+             WHERE content_hash IN (
+                SELECT content_hash
+                FROM packfiles
+                WHERE pack_hash = ?
+             )",
+        )?;
+
+        Ok(remove_stmt.execute(rs::params![hash::to_hex(pack)])?)
+    }
+
+    // Drop any chunk still pointing at the pack, returns the rows removed
+    pub(crate) fn remove_pack(&self, pack: hash::Hash) -> Result<usize, Box<dyn Error>> {
+        let mut remove_stmt = self.db.conn.prepare_cached(
+            "DELETE FROM packfiles
+             WHERE pack_hash = ?",
+        )?;
+        let mut pack_stmt = self.db.conn.prepare_cached(
+            "DELETE FROM packs
+             WHERE pack_hash = ?",
+        )?;
+
+        pack_stmt.execute(rs::params![hash::to_hex(pack)])?;
+        Ok(remove_stmt.execute(rs::params![hash::to_hex(pack)])?)
+    }
+}
+
+// An entry in the index along with where its content is stored
+#[derive(Clone)]
+pub(crate) struct WalkEntry {
+    pub path: String,
+    pub file_type: meta::FileType,
+    pub permission: u32,
+    pub mtime: (i64, i64),
+    pub atime: (i64, i64),
+    pub uid: u32,
+    pub gid: u32,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub xattrs: Vec<meta::Xattr>,
+    pub size: u64,
+    // Data regions of a sparse file, empty when the content simply starts at 0
+    pub extents: Vec<meta::Extent>,
+    // Symlink target + device number of special files
+    pub target: Option<String>,
+    pub rdev: u64,
+    pub link_group: Option<u64>,
+    // Only regular files have content
+    pub content: Option<WalkContent>,
+}
+
+#[derive(Clone)]
+pub(crate) struct WalkContent {
+    // One pack per part of the content, in part order
+    pub packs: Vec<hash::Hash>,
+    pub hash: hash::Hash,
+    pub compression: compress::Compression,
+}
+
+// A pattern matches the path itself or anything under it, so a directory pulls in its children
+fn path_globs(column: &str, start: usize, count: usize) -> String {
+    (start..start + count)
+        .map(|i| format!("{column} GLOB ?{i} OR {column} GLOB ?{i} || '/*'"))
+        .collect::<Vec<_>>()
+        .join(" OR ")
+}
+
+fn file_xattrs(conn: &Connection, file_id: i64) -> Result<Vec<meta::Xattr>, Box<dyn Error>> {
+    let mut xattr_stmt = conn.prepare_cached(
+        "SELECT name, value
+             FROM main.xattrs
+             WHERE file_id = ?
+             ORDER BY rowid ASC;",
+    )?;
+    let xattrs = xattr_stmt
+        .query_map([file_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(xattrs)
+}
+
+fn file_extents(conn: &Connection, file_id: i64) -> Result<Vec<meta::Extent>, Box<dyn Error>> {
+    let mut extent_stmt = conn.prepare_cached(
+        "SELECT offset, length
+             FROM main.extents
+             WHERE file_id = ?
+             ORDER BY rowid ASC;",
+    )?;
+    let mut rows = extent_stmt.query([file_id])?;
+    let mut ret = vec![];
+    while let Some(row) = rows.next()? {
+        let (offset, length): (i64, i64) = (row.get(0)?, row.get(1)?);
+        ret.push((u64::try_from(offset)?, u64::try_from(length)?));
+    }
+    Ok(ret)
 }
 
+// Only the entries that the filter lets through gets walked
 pub(crate) fn walk_files<R, F>(
     index: &mut R,
     map: &mut R,
     key: &key::MemKey,
+    filter: &Filter,
     mut f: F,
 ) -> Result<(), Box<dyn Error>>
 where
-    F: FnMut(&str, u32, hash::Hash, hash::Hash) -> Result<(), Box<dyn Error>>,
+    F: FnMut(&WalkEntry) -> Result<(), Box<dyn Error>>,
     R: Read,
 {
     // Load up the index db
-    println!("Loading INDEX db");
+    info!("Loading INDEX db");
     let idx = Index::load(index, key)?.db;
     let map_file = {
-        println!("Loading MAP db");
+        info!("Loading MAP db");
         let m = Map::load(map, key)?.db;
         let _ = m.conn.close();
         m.db_tmp
     };
     idx.attach(map_file.path(), "map")?;
 
-    // Do query stuff
+    // Filter in the query so that the packs of the skipped entries never gets looked at
+    let (include, exclude) = (&filter.include, &filter.exclude);
+    let mut clauses = vec![];
+    if !include.is_empty() {
+        clauses.push(format!("({})", path_globs("f.path", 1, include.len())));
+    }
+    if !exclude.is_empty() {
+        let globs = path_globs("f.path", include.len() + 1, exclude.len());
+        clauses.push(format!("NOT ({globs})"));
+    }
+    let filter = if clauses.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", clauses.join(" AND "))
+    };
+
+    // Do query stuff, a file split into multiple parts gets one row per part so gather up
+    // the packs of each file before handing it off, entries without content gets a single row
     {
-        let mut dump_stmt = idx.conn.prepare_cached(
-            "SELECT f.path, f.permission, m.pack_hash, f.content_hash
+        let mut dump_stmt = idx.conn.prepare(&format!(
+            "SELECT f.rowid, f.path, f.file_type, f.permission, f.link_target, f.rdev,
+                    f.content_hash, m.pack_hash, m.compression, f.link_group,
+                    f.mtime, f.mtime_nsec, f.atime, f.atime_nsec, f.uid, f.gid, f.user, f.grp,
+                    f.size
                  FROM main.files f
-                 INNER JOIN map.packfiles m ON
-                    m.content_hash = f.content_hash;",
-        )?;
-        let mut rows = dump_stmt.query([])?;
+                 LEFT JOIN map.packfiles m ON
+                    m.content_hash = f.content_hash
+                 {filter}
+                 ORDER BY f.rowid ASC, m.part ASC;"
+        ))?;
+        let mut rows = dump_stmt.query(rs::params_from_iter(include.iter().chain(exclude)))?;
+        let mut current: Option<(i64, WalkEntry)> = None;
+
         while let Ok(Some(row)) = rows.next() {
-            let path: String = row.get(0)?;
-            let perm: u32 = row.get(1)?;
-            let pack: String = row.get(2)?;
-            let hash: String = row.get(3)?;
-
-            f(
-                path.as_str(),
-                perm,
-                hash::from_hex(&pack)?,
-                hash::from_hex(&hash)?,
-            )?;
+            let rowid: i64 = row.get(0)?;
+            let pack: Option<String> = row.get(7)?;
+
+            match current.as_mut() {
+                Some((id, entry)) if *id == rowid => entry
+                    .content
+                    .as_mut()
+                    .ok_or("content")?
+                    .packs
+                    .push(hash::from_hex(&pack.ok_or("pack_hash")?)?),
+                _ => {
+                    if let Some((_, entry)) = current.take() {
+                        f(&entry)?;
+                    }
+
+                    let hash: Option<String> = row.get(6)?;
+                    let content = match (hash, pack) {
+                        (Some(hash), Some(pack)) => Some(WalkContent {
+                            packs: vec![hash::from_hex(&pack)?],
+                            hash: hash::from_hex(&hash)?,
+                            compression: compress::Compression::from_flag(row.get(8)?)?,
+                        }),
+                        (None, _) => None,
+                        (Some(hash), None) => return Err(format!("No pack for: {hash}").into()),
+                    };
+
+                    let rdev: i64 = row.get(5)?;
+                    let size: i64 = row.get(18)?;
+                    let link_group: Option<i64> = row.get(9)?;
+                    current = Some((
+                        rowid,
+                        WalkEntry {
+                            path: row.get(1)?,
+                            file_type: meta::FileType::from_flag(row.get(2)?)?,
+                            permission: row.get(3)?,
+                            mtime: (row.get(10)?, row.get(11)?),
+                            atime: (row.get(12)?, row.get(13)?),
+                            uid: row.get(14)?,
+                            gid: row.get(15)?,
+                            user: row.get(16)?,
+                            group: row.get(17)?,
+                            xattrs: file_xattrs(&idx.conn, rowid)?,
+                            size: u64::try_from(size)?,
+                            extents: file_extents(&idx.conn, rowid)?,
+                            target: row.get(4)?,
+                            rdev: u64::from_ne_bytes(rdev.to_ne_bytes()),
+                            link_group: link_group.map(u64::try_from).transpose()?,
+                            content,
+                        },
+                    ));
+                }
+            }
+        }
+
+        if let Some((_, entry)) = current.take() {
+            f(&entry)?;
         }
     }
 
@@ -289,3 +892,93 @@ where
     let _ = idx.conn.close();
     Ok(())
 }
+
+// Compares two indexes entry by entry, matched up by path, and hands off every entry that
+// differs in path order along with its size (as of the newer index if it is in both)
+pub(crate) fn diff_files<R, F>(
+    old: &mut R,
+    new: &mut R,
+    key: &key::MemKey,
+    mut f: F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(Change, String, u64) -> Result<(), Box<dyn Error>>,
+    R: Read,
+{
+    let idx = Index::load(old, key)?.db;
+    let new_file = {
+        let n = Index::load(new, key)?.db;
+        let _ = n.conn.close();
+        n.db_tmp
+    };
+    idx.attach(new_file.path(), "new")?;
+
+    // These are throwaway copies, index the paths + link groups so the lookups isn't quadratic
+    idx.conn.execute_batch(
+        "CREATE INDEX main.files_path ON files (path);
+         CREATE INDEX new.files_path ON files (path);
+         CREATE INDEX main.files_group ON files (link_group);
+         CREATE INDEX new.files_group ON files (link_group);",
+    )?;
+
+    {
+        let mut diff_stmt = idx.conn.prepare(
+            "SELECT 0, n.path, n.size
+                 FROM new.files n
+                 WHERE NOT EXISTS (SELECT 1 FROM main.files o WHERE o.path = n.path)
+             UNION ALL
+             SELECT 1, o.path, o.size
+                 FROM main.files o
+                 WHERE NOT EXISTS (SELECT 1 FROM new.files n WHERE n.path = o.path)
+             UNION ALL
+             SELECT CASE WHEN o.content_hash IS NOT n.content_hash
+                              OR o.file_type != n.file_type
+                              OR o.size != n.size
+                              OR o.rdev != n.rdev
+                              OR o.link_target IS NOT n.link_target
+                         THEN 2 ELSE 3 END,
+                    n.path, n.size
+                 FROM main.files o
+                 JOIN new.files n ON n.path = o.path
+                 WHERE o.content_hash IS NOT n.content_hash
+                    OR o.file_type != n.file_type
+                    OR o.size != n.size
+                    OR o.permission != n.permission
+                    OR o.mtime != n.mtime
+                    OR o.mtime_nsec != n.mtime_nsec
+                    OR o.uid != n.uid
+                    OR o.gid != n.gid
+                    OR o.user IS NOT n.user
+                    OR o.grp IS NOT n.grp
+                    OR o.rdev != n.rdev
+                    OR o.link_target IS NOT n.link_target
+                    OR EXISTS (SELECT name, value FROM main.xattrs WHERE file_id = o.rowid
+                               EXCEPT
+                               SELECT name, value FROM new.xattrs WHERE file_id = n.rowid)
+                    OR EXISTS (SELECT name, value FROM new.xattrs WHERE file_id = n.rowid
+                               EXCEPT
+                               SELECT name, value FROM main.xattrs WHERE file_id = o.rowid)
+                    OR (SELECT MIN(path) FROM main.files WHERE link_group = o.link_group)
+                       IS NOT
+                       (SELECT MIN(path) FROM new.files WHERE link_group = n.link_group)
+             ORDER BY 2 ASC;",
+        )?;
+        let mut rows = diff_stmt.query([])?;
+
+        while let Some(row) = rows.next()? {
+            let change = match row.get::<_, u8>(0)? {
+                0 => Change::Added,
+                1 => Change::Removed,
+                2 => Change::Modified,
+                _ => Change::Metadata,
+            };
+            let size: i64 = row.get(2)?;
+            f(change, row.get(1)?, u64::try_from(size)?)?;
+        }
+    }
+
+    // Cleanup
+    idx.detach("new")?;
+    let _ = idx.conn.close();
+    Ok(())
+}